@@ -17,7 +17,7 @@ fn main() -> std::io::Result<()> {
 
     prost_build::Config::new()
         // Generate NATS client/server traits and implementations
-        .service_generator(Box::new(NatsServiceGenerator))
+        .service_generator(Box::new(NatsServiceGenerator::default()))
         .compile_protos(&protos, &["proto/"])?;
     Ok(())
 }
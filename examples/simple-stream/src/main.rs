@@ -2,13 +2,17 @@ mod generated {
     include!(concat!(env!("OUT_DIR"), "/simple.rs"));
 }
 
-use futures::{stream, Stream, StreamExt};
+use futures::{stream, stream::BoxStream, Stream, StreamExt};
 use generated::*;
 
 // Implement the service trait
 struct PersonService;
 impl PersonServiceServer for PersonService {
-    async fn get_person(&self, _request: GetPersonRequest) -> anyhow::Result<GetPersonResponse> {
+    async fn get_person(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        _request: GetPersonRequest,
+    ) -> anyhow::Result<GetPersonResponse> {
         Ok(GetPersonResponse {
             person: Some(Person {
                 first_name: "John".to_string(),
@@ -26,6 +30,7 @@ impl PersonServiceServer for PersonService {
 
     async fn get_people(
         &self,
+        _ctx: nats_rpc::RequestContext,
         _request: GetPersonRequest,
     ) -> anyhow::Result<impl Stream<Item = GetPersonResponse>> {
         let person = Person {
@@ -59,6 +64,27 @@ impl PersonServiceServer for PersonService {
             },
         ]))
     }
+
+    async fn count_people(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: BoxStream<'static, Person>,
+    ) -> anyhow::Result<CountPeopleResponse> {
+        let count = request.count().await;
+        Ok(CountPeopleResponse {
+            count: count as i32,
+        })
+    }
+
+    async fn greet_people(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: BoxStream<'static, Person>,
+    ) -> anyhow::Result<impl Stream<Item = Greeting> + Send> {
+        Ok(request.map(|person| Greeting {
+            message: format!("Hello, {}!", person.first_name),
+        }))
+    }
 }
 
 #[tokio::main]
@@ -72,12 +98,13 @@ async fn main() {
     let service = PersonService {};
     let service_handle = tokio::spawn({
         let client = client.clone();
-        start_server(service, client)
+        start_person_service_server(service, client)
             .await
             .expect("should be able to start server")
     });
 
-    // Make a request using the trait on the `async_nats::Client`
+    // Make a request with a client for the service, which uses the default subject prefix
+    let client = PersonServiceNatsClient::new(client);
     let request = GetPersonRequest { id: 42 };
     let person = client
         .get_person(request)
@@ -103,13 +130,15 @@ async fn main() {
         .await
         .expect("should be able to get people");
 
-    // NOTE: it's up to the user to close the stream when done. In this case we know
-    // there is only one person in the stream so we break after the first one.
+    // The stream ends once the server has sent every person
     let mut seen_john = false;
     let mut seen_bob = false;
     let mut seen_alice = false;
     while let Some(person) = people.next().await {
-        let person = person.person.expect("person should be present");
+        let person = person
+            .expect("should be able to receive person")
+            .person
+            .expect("person should be present");
         println!("Person from stream: {person:?}");
         // Ensure the response is what we expect
         match &*person.first_name {
@@ -131,15 +160,56 @@ async fn main() {
         assert_eq!(address.city, "Springfield");
         assert_eq!(address.state, "IL");
         assert_eq!(address.zip_code, "62701");
-        if seen_john && seen_bob && seen_alice {
-            break;
-        }
     }
+    assert!(seen_john && seen_bob && seen_alice);
 
-    // Should be no more items in the stream
-    let remaining_person =
-        tokio::time::timeout(std::time::Duration::from_millis(100), people.next()).await;
-    assert!(remaining_person.is_err());
+    // Send a stream of people to the server, receiving a single response
+    let people = stream::iter(["John", "Bob", "Alice"].map(|first_name| Person {
+        first_name: first_name.to_string(),
+        ..Default::default()
+    }));
+    let counted = client
+        .count_people(people)
+        .await
+        .expect("should be able to count people");
+    assert_eq!(counted.count, 3);
+
+    // Send a stream of people to the server, receiving a stream of greetings
+    let people = stream::iter(["John", "Bob", "Alice"].map(|first_name| Person {
+        first_name: first_name.to_string(),
+        ..Default::default()
+    }));
+    let greetings = client
+        .greet_people(people)
+        .await
+        .expect("should be able to greet people")
+        .map(|greeting| {
+            greeting
+                .expect("should be able to receive greeting")
+                .message
+        })
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(greetings, ["Hello, John!", "Hello, Bob!", "Hello, Alice!"]);
+
+    // Subscribe to greeting events, published by the server with a `PersonServicePublisher`
+    let mut events = client
+        .subscribe_greetings()
+        .await
+        .expect("should be able to subscribe to greetings");
+    let publisher = PersonServicePublisher::new(client.client().clone());
+    publisher
+        .publish_greetings(&Greeting {
+            message: "Hello, everyone!".to_string(),
+        })
+        .await
+        .expect("should be able to publish greeting");
+    let event = events
+        .next()
+        .await
+        .expect("should receive greeting")
+        .expect("should be able to decode greeting");
+    assert_eq!(event.message, "Hello, everyone!");
 
     service_handle.abort();
 }
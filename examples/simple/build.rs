@@ -17,7 +17,12 @@ fn main() -> std::io::Result<()> {
 
     prost_build::Config::new()
         // Generate NATS client/server traits and implementations
-        .service_generator(Box::new(NatsServiceGenerator))
+        // Also emit the in-memory transport used by the tests in main.rs
+        .service_generator(Box::new(
+            NatsServiceGenerator::builder()
+                .loopback_transport(true)
+                .build(),
+        ))
         .compile_protos(&protos, &["proto/"])?;
     Ok(())
 }
@@ -6,7 +6,15 @@ use generated::*;
 // Implement the service trait
 struct PersonService;
 impl PersonServiceServer for PersonService {
-    async fn get_person(&self, _request: GetPersonRequest) -> anyhow::Result<GetPersonResponse> {
+    async fn get_person(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: GetPersonRequest,
+    ) -> anyhow::Result<GetPersonResponse> {
+        // Errors are sent to the caller, use `RemoteError` to choose the code they receive
+        if request.id != 42 {
+            return Err(nats_rpc::RemoteError::new(404, "person not found").into());
+        }
         Ok(GetPersonResponse {
             person: Some(Person {
                 first_name: "John".to_string(),
@@ -34,12 +42,13 @@ async fn main() {
     let service = PersonService {};
     let service_handle = tokio::spawn({
         let client = client.clone();
-        start_server(service, client)
+        start_person_service_server(service, client)
             .await
             .expect("should be able to start server")
     });
 
-    // Make a request using the trait on the `async_nats::Client`
+    // Make a request with a client for the service, which uses the default subject prefix
+    let client = PersonServiceNatsClient::new(client);
     let request = GetPersonRequest { id: 42 };
     let person = client
         .get_person(request)
@@ -59,5 +68,53 @@ async fn main() {
     assert_eq!(address.state, "IL");
     assert_eq!(address.zip_code, "62701");
 
+    // Errors returned by the service are received as a `RemoteError`
+    let err = client
+        .get_person(GetPersonRequest { id: 7 })
+        .await
+        .expect_err("should not find person");
+    let err = err
+        .downcast_ref::<nats_rpc::RemoteError>()
+        .expect("error should come from the service");
+    assert_eq!(err.code, 404);
+    assert_eq!(err.message, "person not found");
+
     service_handle.abort();
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nats_rpc::loopback::LoopbackTransport;
+
+    // The loopback transport runs the service in-process, without a NATS server
+    #[tokio::test]
+    async fn gets_person() {
+        let transport = LoopbackTransport::new();
+        let server = tokio::spawn(
+            start_person_service_server(PersonService, transport.clone())
+                .await
+                .expect("should be able to start server"),
+        );
+        let client = PersonServiceNatsClient::new(transport);
+
+        let person = client
+            .get_person(GetPersonRequest { id: 42 })
+            .await
+            .expect("should be able to get person")
+            .person
+            .expect("person should be present");
+        assert_eq!(person.first_name, "John");
+
+        let err = client
+            .get_person(GetPersonRequest { id: 7 })
+            .await
+            .expect_err("should not find person");
+        let err = err
+            .downcast_ref::<nats_rpc::RemoteError>()
+            .expect("error should come from the service");
+        assert_eq!(err.code, 404);
+
+        server.abort();
+    }
+}
@@ -0,0 +1,313 @@
+// Support for requests and replies too large to send as one message, emitted into the runtime
+// module as `chunks` when `NatsServiceGenerator` is built with `chunking(true)`. `tokio` is
+// brought into scope according to the configured runtime paths.
+
+use super::anyhow::Context as _;
+use super::futures::StreamExt as _;
+use super::{
+    anyhow, async_nats, bytes, chunk_count, chunk_headers, encode, futures, header, headers_len,
+    prost, publish_error, split_payload, without_chunk_headers, CallOptions, RemoteError,
+    Transport, CHUNK_HEADER, SESSION_HEADER,
+};
+
+/// Time to wait for each chunk of a request or reply, and for the receiver of a reply to ask
+/// for the rest of its chunks
+pub const CHUNK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Largest request or reply, in bytes, joined from chunks by default. Larger ones fail with a
+/// `413` error, rather than holding their chunks in memory.
+pub const MAX_CHUNKED_PAYLOAD: usize = 64 * 1024 * 1024;
+
+/// Send a request and wait for its reply, like [Transport::request], splitting a request too
+/// large for one message into chunks.
+///
+/// The first chunk carries the request's headers. The server accepts it with a subject to send
+/// the rest of the chunks to, and replies to the last one. Replies sent in chunks by
+/// [publish_reply] are joined before they're returned.
+pub async fn send_request<T: Transport>(
+    client: &T,
+    subject: String,
+    payload: bytes::Bytes,
+    options: CallOptions,
+) -> anyhow::Result<async_nats::Message> {
+    let CallOptions {
+        timeout,
+        headers,
+        inbox,
+    } = options;
+    let mut chunks = split_payload(client.max_payload(), &headers, payload)?;
+    let reply = if chunks.len() == 1 {
+        let options = CallOptions {
+            timeout,
+            headers,
+            inbox,
+        };
+        client.request(subject, chunks.remove(0), options).await?
+    } else {
+        let (session, headers, last) = send_chunks(client, subject, chunks, headers).await?;
+        // The reply to the last chunk is the reply to the request
+        let options = CallOptions {
+            timeout,
+            headers,
+            inbox,
+        };
+        client
+            .request(session, last, options)
+            .await
+            .context("failed to send the last chunk of the request")?
+    };
+    receive_reply(client, reply).await
+}
+
+/// Publish a request, like [Transport::publish], splitting a request too large for one message
+/// into chunks the way [send_request] does. The last chunk carries the `reply` subject.
+pub async fn publish_request<T: Transport>(
+    client: &T,
+    subject: String,
+    reply: Option<String>,
+    headers: async_nats::HeaderMap,
+    payload: bytes::Bytes,
+) -> anyhow::Result<()> {
+    let mut chunks = split_payload(client.max_payload(), &headers, payload)?;
+    if chunks.len() == 1 {
+        return client
+            .publish(subject, reply, headers, chunks.remove(0))
+            .await;
+    }
+    let (session, headers, last) = send_chunks(client, subject, chunks, headers).await?;
+    client
+        .publish(session, reply, headers, last)
+        .await
+        .context("failed to send the last chunk of the request")
+}
+
+/// Send every chunk of a request but the last, returning the subject the server accepted the
+/// rest of the request on and the last chunk along with its headers
+async fn send_chunks<T: Transport>(
+    client: &T,
+    subject: String,
+    chunks: Vec<bytes::Bytes>,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<(String, async_nats::HeaderMap, bytes::Bytes)> {
+    let total = chunks.len();
+    let mut chunks = chunks.into_iter().enumerate();
+    let (_, first) = chunks.next().context("no chunks to send")?;
+    let options = CallOptions {
+        timeout: Some(CHUNK_TIMEOUT),
+        headers: chunk_headers(&headers, 0, total),
+        inbox: None,
+    };
+    let accepted = client
+        .request(subject, first, options)
+        .await
+        .context("failed to send the first chunk of the request")?;
+    if let Some(error) = RemoteError::from_message(&accepted) {
+        return Err(error.into());
+    }
+    let session = header(&accepted, SESSION_HEADER)
+        .context("server didn't accept the rest of the request")?
+        .to_string();
+    for (index, chunk) in chunks {
+        let headers = chunk_headers(&async_nats::HeaderMap::new(), index, total);
+        if index + 1 == total {
+            return Ok((session, headers, chunk));
+        }
+        client
+            .publish(session.clone(), None, headers, chunk)
+            .await
+            .context("failed to send a chunk of the request")?;
+    }
+    anyhow::bail!("a request is only sent in chunks when there are at least two")
+}
+
+/// Receive the rest of a request sent in chunks by [send_request], returning a message with
+/// the whole request, the headers of its first chunk and the reply subject of its last.
+/// Requests that weren't split are returned as they are, and requests larger than `max_size`
+/// bytes fail without accepting the rest of their chunks when their first chunk shows it.
+pub async fn receive_request<T: Transport>(
+    client: &T,
+    message: async_nats::Message,
+    max_size: usize,
+) -> anyhow::Result<async_nats::Message> {
+    let Some(total) = chunk_count(&message) else {
+        return Ok(message);
+    };
+    check_chunks(&message, total, max_size)?;
+    let reply_to = message
+        .reply
+        .clone()
+        .context("no reply subject found in a request sent in chunks")?;
+    let session = client.new_inbox();
+    let mut chunks = client
+        .subscribe(session.clone(), None)
+        .await
+        .context("failed to subscribe to the rest of the request")?;
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(SESSION_HEADER, session.as_str());
+    client
+        .publish(reply_to.to_string(), None, headers, bytes::Bytes::new())
+        .await
+        .context("failed to accept the rest of the request")?;
+    join_chunks(message, total, &mut chunks, max_size).await
+}
+
+/// Whether the result of a handler fits in one message along with `headers`, so that it can be
+/// replied to without [publish_reply]
+pub fn fits<M: prost::Message>(
+    client: &impl Transport,
+    reply: &anyhow::Result<M>,
+    headers: &async_nats::HeaderMap,
+) -> bool {
+    match reply {
+        Ok(reply) => headers_len(headers) + reply.encoded_len() <= client.max_payload(),
+        Err(_) => true,
+    }
+}
+
+/// Publish the result of a handler to `subject`, like [super::publish_reply], splitting a reply
+/// too large for one message into chunks.
+///
+/// The first chunk carries the reply's headers and a subject for the caller to ask for the rest
+/// of the chunks on, which are sent to the reply subject of the caller's message.
+pub async fn publish_reply<M: prost::Message>(
+    client: &impl Transport,
+    subject: String,
+    reply: anyhow::Result<M>,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => return publish_error(client, subject, &e, headers).await,
+    };
+    let mut chunks = split_payload(client.max_payload(), &headers, encode(&reply))?;
+    if chunks.len() == 1 {
+        return client
+            .publish(subject, None, headers, chunks.remove(0))
+            .await
+            .context("failed to publish reply");
+    }
+    let total = chunks.len();
+    let session = client.new_inbox();
+    let mut pulls = client
+        .subscribe(session.clone(), None)
+        .await
+        .context("failed to subscribe for the caller of a reply sent in chunks")?;
+    let mut chunks = chunks.into_iter().enumerate();
+    let (_, first) = chunks.next().context("no chunks to send")?;
+    let mut first_headers = chunk_headers(&headers, 0, total);
+    first_headers.insert(SESSION_HEADER, session.as_str());
+    client
+        .publish(subject, None, first_headers, first)
+        .await
+        .context("failed to publish the first chunk of the reply")?;
+    let pull = next_chunk(&mut pulls)
+        .await
+        .context("caller didn't ask for the rest of the reply")?;
+    let inbox = pull
+        .reply
+        .context("no reply subject found in the caller's message")?
+        .to_string();
+    for (index, chunk) in chunks {
+        client
+            .publish(
+                inbox.clone(),
+                None,
+                chunk_headers(&async_nats::HeaderMap::new(), index, total),
+                chunk,
+            )
+            .await
+            .context("failed to publish a chunk of the reply")?;
+    }
+    Ok(())
+}
+
+/// Receive the rest of a reply published in chunks by [publish_reply], returning a message with
+/// the whole reply and the headers of its first chunk. Replies that weren't split are returned
+/// as they are, and replies larger than [MAX_CHUNKED_PAYLOAD] bytes fail.
+pub async fn receive_reply<T: Transport>(
+    client: &T,
+    reply: async_nats::Message,
+) -> anyhow::Result<async_nats::Message> {
+    let Some(total) = chunk_count(&reply) else {
+        return Ok(reply);
+    };
+    check_chunks(&reply, total, MAX_CHUNKED_PAYLOAD)?;
+    let session = header(&reply, SESSION_HEADER)
+        .context("no session subject found in a reply sent in chunks")?
+        .to_string();
+    let inbox = client.new_inbox();
+    let mut chunks = client
+        .subscribe(inbox.clone(), None)
+        .await
+        .context("failed to subscribe to the rest of the reply")?;
+    client
+        .publish(
+            session,
+            Some(inbox),
+            async_nats::HeaderMap::new(),
+            bytes::Bytes::new(),
+        )
+        .await
+        .context("failed to ask for the rest of the reply")?;
+    join_chunks(reply, total, &mut chunks, MAX_CHUNKED_PAYLOAD).await
+}
+
+/// Check that a message sent in `total` chunks isn't larger than `max_size` bytes. Every chunk
+/// but the last is as large as the first, which can't be empty.
+fn check_chunks(first: &async_nats::Message, total: usize, max_size: usize) -> anyhow::Result<()> {
+    if first.payload.is_empty() {
+        return Err(RemoteError::new(400, "the first chunk of a message can't be empty").into());
+    }
+    if (total - 1).saturating_mul(first.payload.len()) >= max_size {
+        return Err(too_large(max_size));
+    }
+    Ok(())
+}
+
+fn too_large(max_size: usize) -> anyhow::Error {
+    RemoteError::new(
+        413,
+        format!("message sent in chunks is larger than {max_size} bytes"),
+    )
+    .into()
+}
+
+/// Append the payloads of the chunks after `first` to it, taking the reply subject of the last
+/// chunk that has one, and failing once they add up to more than `max_size` bytes
+async fn join_chunks(
+    mut first: async_nats::Message,
+    total: usize,
+    chunks: &mut (impl futures::Stream<Item = async_nats::Message> + Unpin),
+    max_size: usize,
+) -> anyhow::Result<async_nats::Message> {
+    let mut payload = bytes::BytesMut::from(&first.payload[..]);
+    for index in 1..total {
+        let chunk = next_chunk(chunks).await?;
+        let received = header(&chunk, CHUNK_HEADER).and_then(|i| i.parse::<usize>().ok());
+        if received != Some(index) {
+            anyhow::bail!("expected chunk {index} of {total}, received {received:?}");
+        }
+        if payload.len() + chunk.payload.len() > max_size {
+            return Err(too_large(max_size));
+        }
+        payload.extend_from_slice(&chunk.payload);
+        if chunk.reply.is_some() {
+            first.reply = chunk.reply;
+        }
+    }
+    first.payload = payload.freeze();
+    first.length = first.payload.len();
+    first.headers = first
+        .headers
+        .map(|headers| without_chunk_headers(headers, &[SESSION_HEADER]));
+    Ok(first)
+}
+
+async fn next_chunk(
+    chunks: &mut (impl futures::Stream<Item = async_nats::Message> + Unpin),
+) -> anyhow::Result<async_nats::Message> {
+    match tokio::time::timeout(CHUNK_TIMEOUT, chunks.next()).await {
+        Ok(Some(chunk)) => Ok(chunk),
+        Ok(None) => anyhow::bail!("subscription closed before every chunk was received"),
+        Err(_) => anyhow::bail!("no chunk received after {CHUNK_TIMEOUT:?}"),
+    }
+}
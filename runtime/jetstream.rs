@@ -0,0 +1,700 @@
+// Support for work-queue methods, whose requests are stored in a JetStream stream until a server
+// handles them, and resumable server-streaming methods, whose responses are, emitted into the
+// runtime module as `jetstream` when `NatsServiceGenerator` is built with `jetstream(true)`.
+// `tokio` and `serde_json` are brought into scope according to the configured runtime paths.
+
+use super::anyhow::Context as _;
+use super::async_nats::jetstream::response::Response;
+use super::futures::StreamExt as _;
+use super::{
+    anyhow, async_nats, bytes, encode, frame_headers, futures, header, prost, publish_error,
+    publish_reply, CallOptions, RemoteError, Reply, RequestContext, Transport,
+    DEFAULT_REQUEST_TIMEOUT, END_OF_STREAM, INBOX_HEADER, SEQUENCE_HEADER, STATUS_HEADER,
+};
+
+/// Delay before a request whose handler failed is delivered again for the first time, doubled
+/// on each further attempt
+pub const MIN_REDELIVERY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+/// Longest delay before a request whose handler failed is delivered again
+pub const MAX_REDELIVERY_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
+/// Number of times a request is delivered before its caller gets the error of its last attempt
+pub const MAX_DELIVERIES: i64 = 5;
+/// How long JetStream waits for a server to acknowledge a request before delivering it again
+pub const ACK_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How often a server tells JetStream it's still handling a request, resetting its [ACK_WAIT]
+pub const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Header naming the subject that stores the responses of a resumable call
+pub const CALL_SUBJECT_HEADER: &str = "Nats-Rpc-Call-Subject";
+/// First tokens of the subjects storing the responses of resumable calls, followed by the
+/// service's stream name and an ID for each call
+pub const CALL_SUBJECT_PREFIX: &str = "nats_rpc.responses";
+/// How often JetStream signals that a resumable call is still being delivered while no
+/// responses are, so that clients notice missed responses after two intervals of silence
+pub const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// How long the responses of a completed resumable call are kept, so that a client
+/// disconnected at the end of the call can still resume it, before the server purges them
+pub const RESUME_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+/// Longest time the responses of a resumable call are kept, for calls whose server stopped
+/// before purging them
+pub const MAX_RESPONSE_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// Number of consecutive failed attempts to resume a call before its stream ends with an error
+pub const MAX_RESUME_ATTEMPTS: i64 = 10;
+
+/// Store a request in the JetStream stream capturing `subject` and wait for the server that
+/// handles it to reply.
+///
+/// The reply is sent to the inbox set in `options`, or a new inbox of `client`, which travels
+/// with the request in the [INBOX_HEADER]. The request fails if JetStream doesn't acknowledge
+/// storing it, and the timeout set in `options` covers the wait for the reply.
+pub async fn request<T: Transport>(
+    client: &T,
+    subject: String,
+    payload: bytes::Bytes,
+    options: CallOptions,
+) -> anyhow::Result<async_nats::Message> {
+    let CallOptions {
+        timeout,
+        mut headers,
+        inbox,
+    } = options;
+    let inbox = inbox.unwrap_or_else(|| client.new_inbox());
+    let mut replies = client
+        .subscribe(inbox.clone(), None)
+        .await
+        .context("failed to subscribe for the reply")?;
+    headers.insert(INBOX_HEADER, inbox.as_str());
+    // JetStream acknowledges the request on a separate inbox once it's stored
+    let ack = client
+        .request(
+            subject.clone(),
+            payload,
+            CallOptions {
+                headers,
+                ..CallOptions::default()
+            },
+        )
+        .await
+        .with_context(|| format!("failed to store request on {subject} in JetStream"))?;
+    check_publish_ack(&ack)?;
+
+    let timeout = timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+    match tokio::time::timeout(timeout, replies.next()).await {
+        Ok(Some(reply)) => Ok(reply),
+        Ok(None) => anyhow::bail!("reply subscription closed"),
+        Err(_) => anyhow::bail!("timed out after {timeout:?} waiting for the reply to {subject}"),
+    }
+}
+
+/// JetStream acknowledges a stored message with its stream and sequence, or describes why it
+/// couldn't be stored, e.g. when no stream captures the subject
+fn check_publish_ack(ack: &async_nats::Message) -> anyhow::Result<()> {
+    match serde_json::from_slice(&ack.payload) {
+        Ok(Response::<async_nats::jetstream::publish::PublishAck>::Ok(_)) => Ok(()),
+        Ok(Response::Err { error }) => anyhow::bail!("JetStream didn't store the message: {error}"),
+        Err(e) => Err(anyhow::anyhow!(e)).with_context(|| {
+            format!(
+                "JetStream didn't acknowledge storing the message: {}",
+                String::from_utf8_lossy(&ack.payload)
+            )
+        }),
+    }
+}
+
+/// Check the response to a JetStream API request, ignoring the error with `ignored_code`
+fn check_api_response(
+    response: &async_nats::Message,
+    ignored_code: Option<async_nats::jetstream::ErrorCode>,
+) -> anyhow::Result<()> {
+    match serde_json::from_slice(&response.payload) {
+        Ok(Response::<serde_json::Value>::Ok(_)) => Ok(()),
+        Ok(Response::Err { error }) if Some(error.error_code()) == ignored_code => Ok(()),
+        Ok(Response::Err { error }) => anyhow::bail!("JetStream API returned an error: {error}"),
+        Err(e) => Err(anyhow::anyhow!(e)).with_context(|| {
+            format!(
+                "JetStream API returned an invalid response: {}",
+                String::from_utf8_lossy(&response.payload)
+            )
+        }),
+    }
+}
+
+/// Get the work-queue stream named `name` capturing `subjects`, creating it if it doesn't exist
+pub async fn work_queue(
+    client: &async_nats::Client,
+    name: &str,
+    subjects: Vec<String>,
+) -> anyhow::Result<async_nats::jetstream::stream::Stream> {
+    async_nats::jetstream::new(client.clone())
+        .get_or_create_stream(async_nats::jetstream::stream::Config {
+            name: name.to_string(),
+            subjects,
+            retention: async_nats::jetstream::stream::RetentionPolicy::WorkQueue,
+            ..Default::default()
+        })
+        .await
+        .with_context(|| format!("failed to create JetStream stream {name}"))
+}
+
+/// Pull the requests stored on `subject` through the durable consumer `name`, creating it if it
+/// doesn't exist. Servers sharing a consumer each receive a share of the requests.
+pub async fn consume(
+    stream: &async_nats::jetstream::stream::Stream,
+    name: &str,
+    subject: String,
+) -> anyhow::Result<futures::stream::BoxStream<'static, async_nats::Message>> {
+    let consumer = stream
+        .get_or_create_consumer(
+            name,
+            async_nats::jetstream::consumer::pull::Config {
+                durable_name: Some(name.to_string()),
+                filter_subject: subject,
+                ack_policy: async_nats::jetstream::consumer::AckPolicy::Explicit,
+                ack_wait: ACK_WAIT,
+                max_deliver: MAX_DELIVERIES,
+                ..Default::default()
+            },
+        )
+        .await
+        .with_context(|| format!("failed to create JetStream consumer {name}"))?;
+    let messages = consumer
+        .messages()
+        .await
+        .with_context(|| format!("failed to pull messages for JetStream consumer {name}"))?;
+    // The consumer keeps pulling after errors such as missed heartbeats. Requests are
+    // acknowledged by publishing to their reply subject, so they're handled as core messages.
+    Ok(messages
+        .filter_map(|message| async move {
+            message
+                .map(|message| message.message)
+                .map_err(|e| eprintln!("failed to pull work-queue request: {e}"))
+                .ok()
+        })
+        .boxed())
+}
+
+/// Create the context of a work-queue request, whose response goes to the inbox named in its
+/// [INBOX_HEADER] rather than the subject JetStream expects acknowledgements on
+pub fn context(message: &async_nats::Message) -> RequestContext {
+    let mut ctx = RequestContext::new(message);
+    ctx.reply = header(message, INBOX_HEADER).map(ToString::to_string);
+    ctx
+}
+
+/// Run the `handler` of a work-queue request, telling JetStream every [PROGRESS_INTERVAL] that
+/// the request is still being handled, so that handlers taking longer than [ACK_WAIT] don't
+/// have it delivered again while they run
+pub async fn in_progress<T: Transport, F: std::future::Future>(
+    client: &T,
+    message: &async_nats::Message,
+    handler: F,
+) -> F::Output {
+    let mut handler = std::pin::pin!(handler);
+    loop {
+        match tokio::time::timeout(PROGRESS_INTERVAL, handler.as_mut()).await {
+            Ok(output) => return output,
+            Err(_) => {
+                let progress = async_nats::jetstream::AckKind::Progress;
+                if let Err(e) = acknowledge(client, message, progress).await {
+                    eprintln!("{e:#}");
+                }
+            }
+        }
+    }
+}
+
+/// Reply to a work-queue request with the result of its handler, along with `headers`, then
+/// tell JetStream whether it's done with the request.
+///
+/// Successful requests and requests failing with a [RemoteError] code in the 4xx range, which
+/// would fail again, are removed from the stream once the caller has its reply. Any other
+/// error is returned without replying, and JetStream delivers the request again after
+/// [redelivery_delay]. Once its [MAX_DELIVERIES]th delivery fails, the caller gets the error,
+/// which is also returned, and the request is removed.
+pub async fn complete<T: Transport, M: prost::Message>(
+    client: &T,
+    message: &async_nats::Message,
+    reply: anyhow::Result<M>,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    let inbox = header(message, INBOX_HEADER).map(ToString::to_string);
+    let delivered = ack_token(message, 0).map_or(1, |delivered| delivered as i64);
+    match reply {
+        Err(e) if !is_client_error(&e) && delivered < MAX_DELIVERIES => {
+            let delay = redelivery_delay(delivered);
+            acknowledge(
+                client,
+                message,
+                async_nats::jetstream::AckKind::Nak(Some(delay)),
+            )
+            .await?;
+            Err(e.context("request will be delivered again"))
+        }
+        Err(e) if !is_client_error(&e) => {
+            if let Some(inbox) = inbox {
+                publish_error(client, inbox, &e, headers).await?;
+            }
+            acknowledge(client, message, async_nats::jetstream::AckKind::Term).await?;
+            Err(e.context(format!("request failed after {delivered} deliveries")))
+        }
+        Err(e) => {
+            if let Some(inbox) = inbox {
+                publish_error(client, inbox, &e, headers).await?;
+            }
+            acknowledge(client, message, async_nats::jetstream::AckKind::Term).await
+        }
+        Ok(reply) => {
+            if let Some(inbox) = inbox {
+                publish_reply(client, inbox, Ok(reply), headers).await?;
+            }
+            acknowledge(client, message, async_nats::jetstream::AckKind::Ack).await
+        }
+    }
+}
+
+/// Delay before a request is delivered again after the handling of its `delivered`th delivery
+/// failed, doubling from [MIN_REDELIVERY_DELAY] up to [MAX_REDELIVERY_DELAY]
+pub fn redelivery_delay(delivered: i64) -> std::time::Duration {
+    // The exponent is capped so the delay is computed without overflowing
+    let doublings = delivered.clamp(1, 17) as u32 - 1;
+    MIN_REDELIVERY_DELAY
+        .saturating_mul(1 << doublings)
+        .min(MAX_REDELIVERY_DELAY)
+}
+
+fn is_client_error(error: &anyhow::Error) -> bool {
+    (400..500).contains(&RemoteError::from(error).code)
+}
+
+/// Tell JetStream what to do with a request by publishing `kind` to its reply subject
+async fn acknowledge<T: Transport>(
+    client: &T,
+    message: &async_nats::Message,
+    kind: async_nats::jetstream::AckKind,
+) -> anyhow::Result<()> {
+    let ack_subject = message
+        .reply
+        .as_ref()
+        .context("no subject to acknowledge work-queue request on")?;
+    client
+        .publish(
+            ack_subject.to_string(),
+            None,
+            async_nats::HeaderMap::new(),
+            kind.into(),
+        )
+        .await
+        .context("failed to acknowledge work-queue request")
+}
+
+/// Name of the stream storing the responses of resumable calls to the services storing their
+/// requests in `stream`
+pub fn responses_stream(stream: &str) -> String {
+    format!("{stream}_RESPONSES")
+}
+
+/// Create the stream storing the responses of resumable calls to the services storing their
+/// requests in `stream`, unless it exists. An existing stream is used as it is, even if its
+/// configuration differs.
+///
+/// JetStream is managed through its API subjects, rather than `async_nats::jetstream`, so
+/// that any [Transport] can be used.
+pub async fn create_responses_stream<T: Transport>(client: &T, stream: &str) -> anyhow::Result<()> {
+    let name = responses_stream(stream);
+    let config = serde_json::json!({
+        "name": name,
+        "subjects": [format!("{CALL_SUBJECT_PREFIX}.{stream}.>")],
+        "retention": "limits",
+        "storage": "file",
+        "discard": "old",
+        "max_age": nanos(MAX_RESPONSE_AGE),
+        "num_replicas": 1,
+    });
+    let response = client
+        .request(
+            format!("$JS.API.STREAM.CREATE.{name}"),
+            config.to_string().into(),
+            CallOptions::default(),
+        )
+        .await
+        .with_context(|| format!("failed to create JetStream stream {name}"))?;
+    check_api_response(
+        &response,
+        Some(async_nats::jetstream::ErrorCode::STREAM_NAME_EXIST),
+    )
+    .with_context(|| format!("failed to create JetStream stream {name}"))
+}
+
+/// The stream storing the responses of resumable calls to the services storing their requests
+/// in a stream, created by the first call a server handles rather than when it starts, so that
+/// servers whose transport has no JetStream can still serve their other methods
+pub struct ResponsesStream {
+    stream: String,
+    created: std::sync::atomic::AtomicBool,
+}
+
+impl ResponsesStream {
+    /// Create the responses stream of the services storing their requests in `stream`
+    pub fn new(stream: &str) -> Self {
+        Self {
+            stream: stream.to_string(),
+            created: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Create the stream with [create_responses_stream] unless this server already has. Calls
+    /// racing to create it each do, which JetStream accepts.
+    async fn create<T: Transport>(&self, client: &T) -> anyhow::Result<()> {
+        if self.created.load(std::sync::atomic::Ordering::Acquire) {
+            return Ok(());
+        }
+        create_responses_stream(client, &self.stream).await?;
+        self.created
+            .store(true, std::sync::atomic::Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Send the request of a resumable server-streaming call to `subject`, returning the stream of
+/// responses the server stores in JetStream.
+///
+/// Responses are received through a consumer of the call's subject. When it goes quiet for
+/// two [HEARTBEAT_INTERVAL]s, such as after a reconnect, or a response is missed, the consumer
+/// is replaced by one starting after the last response received, so the stream continues
+/// without losing or repeating responses.
+pub async fn call_resumable<T, M>(
+    client: &T,
+    subject: String,
+    stream: &str,
+    payload: bytes::Bytes,
+    options: CallOptions,
+) -> anyhow::Result<futures::stream::BoxStream<'static, anyhow::Result<Reply<M>>>>
+where
+    T: Transport,
+    M: prost::Message + Default + Send + 'static,
+{
+    let call_id = client.new_inbox().replace('.', "_");
+    let mut call = ResumableCall {
+        client: client.clone(),
+        stream: responses_stream(stream),
+        subject: format!("{CALL_SUBJECT_PREFIX}.{stream}.{call_id}"),
+        consumer: None,
+        deliveries: None,
+        sequence: 0,
+        stream_sequence: None,
+        failed_attempts: 0,
+    };
+    // The consumer delivers every response stored on the call's subject, whenever it's created
+    call.consume()
+        .await
+        .context("failed to consume the responses of resumable call")?;
+
+    let CallOptions {
+        timeout,
+        mut headers,
+        inbox,
+    } = options;
+    headers.insert(CALL_SUBJECT_HEADER, call.subject.as_str());
+    let accepted = client
+        .request(
+            subject,
+            payload,
+            CallOptions {
+                timeout,
+                headers,
+                inbox,
+            },
+        )
+        .await
+        .context("failed to send resumable call")?;
+    if let Some(error) = RemoteError::from_message(&accepted) {
+        return Err(error.into());
+    }
+
+    Ok(futures::stream::unfold(Some(call), |call| async move {
+        let mut call = call?;
+        match call.next::<M>().await {
+            Ok(Some(reply)) => Some((Ok(reply), Some(call))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .boxed())
+}
+
+/// The client side of a resumable call, following the responses stored on its subject
+struct ResumableCall<T: Transport> {
+    client: T,
+    /// Stream storing the responses
+    stream: String,
+    /// Subject storing the responses of this call
+    subject: String,
+    /// Consumer delivering the responses, and its deliveries
+    consumer: Option<String>,
+    deliveries: Option<T::Subscriber>,
+    /// Position of the next response in the call
+    sequence: u64,
+    /// Stream sequence of the last response received
+    stream_sequence: Option<u64>,
+    /// Attempts to resume the call since the last response was received
+    failed_attempts: i64,
+}
+
+impl<T: Transport> ResumableCall<T> {
+    /// Receive the next response, resuming the call when responses go missing, or `None`
+    /// once the server has ended the call
+    async fn next<M: prost::Message + Default>(&mut self) -> anyhow::Result<Option<Reply<M>>> {
+        loop {
+            let delivery = match self.deliveries.as_mut() {
+                Some(deliveries) => {
+                    tokio::time::timeout(2 * HEARTBEAT_INTERVAL, deliveries.next()).await
+                }
+                None => Ok(None),
+            };
+            let message = match delivery {
+                Ok(Some(message)) => message,
+                // Neither responses nor heartbeats arrive while disconnected
+                Ok(None) | Err(_) => {
+                    self.resume().await?;
+                    continue;
+                }
+            };
+            // Heartbeats carry a status rather than a response
+            if message.status.is_some() {
+                continue;
+            }
+            let received = header(&message, SEQUENCE_HEADER).and_then(|s| s.parse::<u64>().ok());
+            match received {
+                // Delivered again by the consumer that replaced a previous one
+                Some(received) if received < self.sequence => continue,
+                Some(received) if received == self.sequence => {}
+                Some(_) => {
+                    self.resume().await?;
+                    continue;
+                }
+                None => anyhow::bail!("received response without a sequence on {}", self.subject),
+            }
+            self.failed_attempts = 0;
+            self.stream_sequence = ack_token(&message, 1).or(self.stream_sequence);
+            if header(&message, STATUS_HEADER) == Some(END_OF_STREAM) {
+                return Ok(None);
+            }
+            self.sequence += 1;
+            let decoded = M::decode(message.payload).context("failed to decode stream frame")?;
+            return Ok(Some(Reply {
+                message: decoded,
+                headers: message.headers.unwrap_or_default(),
+            }));
+        }
+    }
+
+    /// Replace the consumer delivering the responses, backing off between failed attempts
+    async fn resume(&mut self) -> anyhow::Result<()> {
+        self.deliveries = None;
+        loop {
+            self.failed_attempts += 1;
+            if self.failed_attempts > MAX_RESUME_ATTEMPTS {
+                anyhow::bail!(
+                    "failed to resume call on {} after {MAX_RESUME_ATTEMPTS} attempts",
+                    self.subject
+                );
+            }
+            // Resuming backs off like the redelivery of failed work-queue requests
+            tokio::time::sleep(redelivery_delay(self.failed_attempts)).await;
+            match self.consume().await {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("failed to resume call on {}: {e:#}", self.subject),
+            }
+        }
+    }
+
+    /// Deliver the responses after the last one received through a new consumer
+    async fn consume(&mut self) -> anyhow::Result<()> {
+        if let Some(consumer) = self.consumer.take() {
+            // The previous consumer is removed once inactive if it can't be deleted now
+            let _ = self
+                .client
+                .request(
+                    format!("$JS.API.CONSUMER.DELETE.{}.{consumer}", self.stream),
+                    bytes::Bytes::new(),
+                    CallOptions::default(),
+                )
+                .await;
+        }
+        let deliver_subject = self.client.new_inbox();
+        let deliveries = self
+            .client
+            .subscribe(deliver_subject.clone(), None)
+            .await
+            .context("failed to subscribe for responses")?;
+        let consumer = deliver_subject.replace('.', "_");
+        let mut config = serde_json::json!({
+            "name": consumer,
+            "deliver_subject": deliver_subject,
+            "filter_subject": self.subject,
+            "deliver_policy": "all",
+            "ack_policy": "none",
+            "idle_heartbeat": nanos(HEARTBEAT_INTERVAL),
+            "inactive_threshold": nanos(RESUME_WINDOW),
+            "mem_storage": true,
+            "num_replicas": 1,
+        });
+        // Resume after the last response received
+        if let Some(sequence) = self.stream_sequence {
+            config["deliver_policy"] = "by_start_sequence".into();
+            config["opt_start_seq"] = (sequence + 1).into();
+        }
+        let request = serde_json::json!({ "stream_name": self.stream, "config": config });
+        let response = self
+            .client
+            .request(
+                format!("$JS.API.CONSUMER.CREATE.{}.{consumer}", self.stream),
+                request.to_string().into(),
+                CallOptions::default(),
+            )
+            .await
+            .context("failed to create JetStream consumer")?;
+        check_api_response(&response, None)?;
+        self.consumer = Some(consumer);
+        self.deliveries = Some(deliveries);
+        Ok(())
+    }
+}
+
+/// Read a number from the reply subject of a message delivered by a JetStream consumer, `0`
+/// for the number of times it was delivered and `1` for its stream sequence. The subject has
+/// either the `$JS.ACK.<stream>.<consumer>.<delivered>.<stream sequence>...` form or the form
+/// that adds a domain and account hash after `$JS.ACK`.
+fn ack_token(message: &async_nats::Message, offset: usize) -> Option<u64> {
+    let tokens = message.reply.as_ref()?.split('.').collect::<Vec<_>>();
+    let index = match tokens.len() {
+        9 => 4,
+        len if len >= 12 => 6,
+        _ => return None,
+    };
+    if tokens[..2] != ["$JS", "ACK"] {
+        return None;
+    }
+    tokens[index + offset].parse().ok()
+}
+
+/// Reply to a resumable server-streaming call with the result of its handler, along with
+/// `headers`, storing each response on the subject named by the call's [CALL_SUBJECT_HEADER].
+///
+/// The responses stream is created by the first call, and an error is returned if it can't be.
+/// The caller is then told the call was accepted, or receives the handler's error, as the reply
+/// to its request. The responses are then stored in order, followed by an end-of-stream marker,
+/// and purged [RESUME_WINDOW] after the call completes.
+pub async fn serve_resumable<T, M>(
+    client: &T,
+    message: &async_nats::Message,
+    responses: &ResponsesStream,
+    replies: anyhow::Result<impl futures::Stream<Item = M> + Send>,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()>
+where
+    T: Transport,
+    M: prost::Message + Send,
+{
+    let Some(reply_to) = message.reply.as_ref().map(ToString::to_string) else {
+        eprintln!("No reply subject found in message");
+        return Ok(());
+    };
+    let stream = responses.stream.as_str();
+    // Calls can only store responses in the subjects of their service's stream
+    let prefix = format!("{CALL_SUBJECT_PREFIX}.{stream}.");
+    let call_subject = match header(message, CALL_SUBJECT_HEADER) {
+        Some(subject)
+            if subject.len() > prefix.len()
+                && subject.starts_with(&prefix)
+                && !subject[prefix.len()..].contains(['.', '*', '>']) =>
+        {
+            subject.to_string()
+        }
+        _ => {
+            let error = RemoteError::new(
+                400,
+                format!(
+                    "resumable calls must set {CALL_SUBJECT_HEADER} to a subject under {prefix}"
+                ),
+            );
+            return publish_error(client, reply_to, &error.into(), headers).await;
+        }
+    };
+    let replies = match replies {
+        Ok(replies) => replies,
+        Err(e) => return publish_error(client, reply_to, &e, headers).await,
+    };
+    responses.create(client).await?;
+    client
+        .publish(reply_to, None, headers.clone(), bytes::Bytes::new())
+        .await
+        .context("failed to accept resumable call")?;
+
+    futures::pin_mut!(replies);
+    let mut sequence = 0u64;
+    while let Some(reply) = replies.next().await {
+        store(
+            client,
+            call_subject.clone(),
+            frame_headers(&headers, sequence),
+            encode(&reply),
+        )
+        .await?;
+        sequence += 1;
+    }
+    let mut end = frame_headers(&headers, sequence);
+    end.insert(STATUS_HEADER, END_OF_STREAM);
+    store(client, call_subject.clone(), end, bytes::Bytes::new()).await?;
+
+    // Clients disconnected at the end of the call can resume it until it's purged
+    let client = client.clone();
+    let stream = responses_stream(stream);
+    tokio::spawn(async move {
+        tokio::time::sleep(RESUME_WINDOW).await;
+        if let Err(e) = purge(&client, &stream, &call_subject).await {
+            eprintln!("{e:#}");
+        }
+    });
+    Ok(())
+}
+
+/// Store a response of a resumable call, waiting for JetStream to acknowledge it
+async fn store<T: Transport>(
+    client: &T,
+    subject: String,
+    headers: async_nats::HeaderMap,
+    payload: bytes::Bytes,
+) -> anyhow::Result<()> {
+    let ack = client
+        .request(
+            subject,
+            payload,
+            CallOptions {
+                headers,
+                ..CallOptions::default()
+            },
+        )
+        .await
+        .context("failed to store response in JetStream")?;
+    check_publish_ack(&ack)
+}
+
+/// Durations are sent to the JetStream API in nanoseconds
+fn nanos(duration: std::time::Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+/// Remove the responses stored on `subject` from `stream`
+async fn purge<T: Transport>(client: &T, stream: &str, subject: &str) -> anyhow::Result<()> {
+    let response = client
+        .request(
+            format!("$JS.API.STREAM.PURGE.{stream}"),
+            serde_json::json!({ "filter": subject }).to_string().into(),
+            CallOptions::default(),
+        )
+        .await
+        .with_context(|| format!("failed to purge resumable call on {subject}"))?;
+    check_api_response(&response, None)
+        .with_context(|| format!("failed to purge resumable call on {subject}"))
+}
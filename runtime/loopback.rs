@@ -0,0 +1,281 @@
+// In-memory transport for generated NATS services, emitted into the runtime module as `loopback`
+// when `NatsServiceGenerator` is built with `loopback_transport(true)`. `tokio` is brought into
+// scope according to the configured runtime paths.
+
+use super::futures::StreamExt as _;
+use super::{
+    anyhow, async_nats, bytes, futures, CallOptions, Transport, DEFAULT_MAX_PAYLOAD,
+    DEFAULT_REQUEST_TIMEOUT,
+};
+
+/// A [Transport] that delivers messages between clients and servers in the same process, so
+/// generated services can be tested without a NATS server.
+///
+/// Clones share the same subscriptions, so a server started with one clone receives the
+/// requests of clients using another. Subjects support the `*` and `>` wildcards, messages for
+/// a queue group are handed to its members in turn, and requests fail immediately when nothing
+/// is subscribed to their subject. Like NATS, messages larger than the transport's maximum
+/// payload are rejected.
+///
+/// # Usage
+/// ```rust,ignore
+/// let transport = nats_rpc::loopback::LoopbackTransport::new();
+/// let server = start_person_service_server(MyServer, transport.clone()).await?;
+/// tokio::spawn(server);
+/// let client = PersonServiceNatsClient::new(transport);
+/// let response = client.get_person(request).await?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct LoopbackTransport {
+    router: std::sync::Arc<std::sync::Mutex<Router>>,
+    max_payload: Option<usize>,
+}
+
+/// The subscriptions of a [LoopbackTransport] and its clones
+#[derive(Debug, Default)]
+struct Router {
+    next_id: u64,
+    subscriptions: std::collections::BTreeMap<u64, Subscription>,
+    /// Number of messages handed to each queue group, used to pick the next member
+    queue_turns: std::collections::HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    subject: String,
+    queue_group: Option<String>,
+    sender: futures::channel::mpsc::UnboundedSender<async_nats::Message>,
+}
+
+impl LoopbackTransport {
+    /// Create a transport with no subscriptions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject messages larger than `max_payload` bytes, headers included, instead of
+    /// [DEFAULT_MAX_PAYLOAD]
+    pub fn with_max_payload(mut self, max_payload: usize) -> Self {
+        self.max_payload = Some(max_payload);
+        self
+    }
+
+    fn check_payload(
+        &self,
+        headers: &async_nats::HeaderMap,
+        payload: &bytes::Bytes,
+    ) -> anyhow::Result<()> {
+        let size = super::headers_len(headers) + payload.len();
+        if size > self.max_payload() {
+            anyhow::bail!(
+                "message of {size} bytes exceeds the maximum payload of {} bytes",
+                self.max_payload()
+            );
+        }
+        Ok(())
+    }
+
+    fn router(&self) -> std::sync::MutexGuard<'_, Router> {
+        // The router is left consistent by every operation, so a poisoned lock is still usable
+        self.router
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Deliver a message to every matching subscription and to one member of each matching
+    /// queue group, returning the number of subscriptions it was delivered to
+    fn deliver(&self, message: async_nats::Message) -> usize {
+        let mut router = self.router();
+        let router = &mut *router;
+        // Subscriptions whose subscriber has been dropped no longer receive messages
+        router
+            .subscriptions
+            .retain(|_, subscription| !subscription.sender.is_closed());
+
+        let mut targets = Vec::new();
+        let mut groups = std::collections::BTreeMap::<&str, Vec<&Subscription>>::new();
+        for subscription in router.subscriptions.values() {
+            if !subject_matches(&subscription.subject, message.subject.as_str()) {
+                continue;
+            }
+            match &subscription.queue_group {
+                Some(queue_group) => groups
+                    .entry(queue_group.as_str())
+                    .or_default()
+                    .push(subscription),
+                None => targets.push(subscription),
+            }
+        }
+        for (queue_group, members) in groups {
+            let turn = router
+                .queue_turns
+                .entry(queue_group.to_string())
+                .or_default();
+            targets.push(members[*turn % members.len()]);
+            *turn = turn.wrapping_add(1);
+        }
+
+        targets
+            .into_iter()
+            .filter(|subscription| subscription.sender.unbounded_send(message.clone()).is_ok())
+            .count()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    type Subscriber = LoopbackSubscriber;
+
+    async fn request(
+        &self,
+        subject: String,
+        payload: bytes::Bytes,
+        options: CallOptions,
+    ) -> anyhow::Result<async_nats::Message> {
+        let inbox = options.inbox.unwrap_or_else(|| self.new_inbox());
+        let mut replies = self.subscribe(inbox.clone(), None).await?;
+        check_publish_subject(&subject)?;
+        self.check_payload(&options.headers, &payload)?;
+        let delivered = self.deliver(message(
+            subject.clone(),
+            Some(inbox),
+            options.headers,
+            payload,
+        ));
+        if delivered == 0 {
+            anyhow::bail!("no responders for request on {subject}");
+        }
+        let timeout = options.timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        match tokio::time::timeout(timeout, replies.next()).await {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) => anyhow::bail!("reply subscription closed"),
+            Err(_) => anyhow::bail!("request on {subject} timed out after {timeout:?}"),
+        }
+    }
+
+    async fn publish(
+        &self,
+        subject: String,
+        reply: Option<String>,
+        headers: async_nats::HeaderMap,
+        payload: bytes::Bytes,
+    ) -> anyhow::Result<()> {
+        check_publish_subject(&subject)?;
+        self.check_payload(&headers, &payload)?;
+        // Like NATS, publishing succeeds whether or not anything is subscribed
+        self.deliver(message(subject, reply, headers, payload));
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        subject: String,
+        queue_group: Option<String>,
+    ) -> anyhow::Result<Self::Subscriber> {
+        if subject.is_empty() || subject.split('.').any(str::is_empty) {
+            anyhow::bail!("invalid subject `{subject}`");
+        }
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut router = self.router();
+        let id = router.next_id;
+        router.next_id += 1;
+        router.subscriptions.insert(
+            id,
+            Subscription {
+                subject,
+                queue_group,
+                sender,
+            },
+        );
+        Ok(LoopbackSubscriber {
+            id,
+            router: std::sync::Arc::downgrade(&self.router),
+            receiver,
+        })
+    }
+
+    fn new_inbox(&self) -> String {
+        let mut router = self.router();
+        let id = router.next_id;
+        router.next_id += 1;
+        format!("_INBOX.loopback.{id}")
+    }
+
+    fn max_payload(&self) -> usize {
+        self.max_payload.unwrap_or(DEFAULT_MAX_PAYLOAD)
+    }
+}
+
+/// The messages received by a [LoopbackTransport] subscription, which is removed when this is
+/// dropped
+#[derive(Debug)]
+pub struct LoopbackSubscriber {
+    id: u64,
+    router: std::sync::Weak<std::sync::Mutex<Router>>,
+    receiver: futures::channel::mpsc::UnboundedReceiver<async_nats::Message>,
+}
+
+impl futures::Stream for LoopbackSubscriber {
+    type Item = async_nats::Message;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for LoopbackSubscriber {
+    fn drop(&mut self) {
+        if let Some(router) = self.router.upgrade() {
+            router
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .subscriptions
+                .remove(&self.id);
+        }
+    }
+}
+
+fn message(
+    subject: String,
+    reply: Option<String>,
+    headers: async_nats::HeaderMap,
+    payload: bytes::Bytes,
+) -> async_nats::Message {
+    async_nats::Message {
+        subject: subject.into(),
+        reply: reply.map(Into::into),
+        length: payload.len(),
+        payload,
+        headers: (!headers.is_empty()).then_some(headers),
+        status: None,
+        description: None,
+    }
+}
+
+/// Messages are published to concrete subjects, without wildcards or empty tokens
+fn check_publish_subject(subject: &str) -> anyhow::Result<()> {
+    if subject
+        .split('.')
+        .any(|token| token.is_empty() || token == "*" || token == ">")
+    {
+        anyhow::bail!("can't publish to `{subject}`");
+    }
+    Ok(())
+}
+
+/// Whether `subject` matches the subscription subject `pattern`, where `*` matches any single
+/// token and a trailing `>` matches one or more tokens
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(subject_token)) if token == subject_token => {}
+            _ => return false,
+        }
+    }
+    subject_tokens.next().is_none()
+}
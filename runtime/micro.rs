@@ -0,0 +1,98 @@
+// Support for serving generated services through the NATS services API, emitted into the runtime
+// module as `micro` when `NatsServiceGenerator` is built with `micro_service(true)`.
+
+use super::anyhow::Context as _;
+use super::{anyhow, async_nats, bytes, encode, prost, publish_error, publish_reply, RemoteError};
+
+/// Reply to a request received by an `async_nats::service` endpoint with the result of a
+/// handler, along with `headers`.
+///
+/// Every reply goes through [async_nats::service::Request::respond], so that it's counted in
+/// the endpoint's stats. `respond` can't send headers or error details, so replies that have
+/// them are published directly and then [recorded](record).
+pub async fn respond<M: prost::Message>(
+    client: &async_nats::Client,
+    request: async_nats::service::Request,
+    reply: anyhow::Result<M>,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => return respond_error(client, request, &e, headers).await,
+    };
+    let reply_to = reply_subject(&request)?;
+    if headers.is_empty() {
+        return request
+            .respond(Ok(encode(&reply)))
+            .await
+            .context("failed to publish reply");
+    }
+    publish_reply(client, reply_to, Ok(reply), headers).await?;
+    record(client, request, None).await
+}
+
+/// Reply to a request received by an `async_nats::service` endpoint with an error, along with
+/// `headers`, counting it in the endpoint's stats like [respond]
+pub async fn respond_error(
+    client: &async_nats::Client,
+    request: async_nats::service::Request,
+    error: &anyhow::Error,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    let reply_to = reply_subject(&request)?;
+    let remote = RemoteError::from(error);
+    if headers.is_empty() && remote.details.is_none() {
+        return request
+            .respond(Err(service_error(remote)))
+            .await
+            .context("failed to publish error reply");
+    }
+    publish_error(client, reply_to, error, headers).await?;
+    record(client, request, Some(remote)).await
+}
+
+/// Count a request whose reply was published without [respond], such as a reply sent in
+/// chunks or the responses of a streaming call, in the endpoint's stats, as an error if `error`
+/// is set.
+///
+/// [async_nats::service::Request::respond] is the only way to update the stats, and it always
+/// publishes a reply, so it's pointed at a new inbox that nothing is subscribed to. Each call
+/// recorded this way costs one extra, empty publish.
+pub async fn record(
+    client: &async_nats::Client,
+    mut request: async_nats::service::Request,
+    error: Option<RemoteError>,
+) -> anyhow::Result<()> {
+    request.message.reply = Some(client.new_inbox().into());
+    request
+        .respond(error.map_or(Ok(bytes::Bytes::new()), |error| Err(service_error(error))))
+        .await
+        .context("failed to record reply in the endpoint's stats")
+}
+
+/// Requests without a reply subject fail, so the server passes them to its `on_error` hook
+fn reply_subject(request: &async_nats::service::Request) -> anyhow::Result<String> {
+    request
+        .message
+        .reply
+        .as_ref()
+        .map(ToString::to_string)
+        .context("no reply subject found in message")
+}
+
+fn service_error(error: RemoteError) -> async_nats::service::error::Error {
+    async_nats::service::error::Error {
+        status: error.message,
+        code: error.code.into(),
+    }
+}
+
+/// Metadata attached to a service or endpoint, from its `metadata` proto option
+pub fn metadata<const N: usize>(
+    entries: [(&str, &str); N],
+) -> std::collections::HashMap<String, String> {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
@@ -0,0 +1,1025 @@
+// Runtime support for generated NATS services. This file is emitted into generated code by
+// `NatsServiceGenerator`, with `anyhow`, `async_nats`, `bytes`, `futures` and `prost` brought
+// into scope according to the configured runtime paths.
+
+use self::anyhow::Context as _;
+use self::futures::StreamExt as _;
+
+/// Header set by a client opening a streaming call, naming the inbox that receives responses
+pub const INBOX_HEADER: &str = "Nats-Rpc-Inbox";
+/// Header set by a server accepting a streaming call, naming the subject that receives requests
+pub const SESSION_HEADER: &str = "Nats-Rpc-Session";
+/// Header carrying the position of a frame in a stream, starting at 0
+pub const SEQUENCE_HEADER: &str = "Nats-Rpc-Seq";
+/// Header describing the status of a message
+pub const STATUS_HEADER: &str = "Nats-Rpc-Status";
+/// Value of the [STATUS_HEADER] on the message that ends a stream
+pub const END_OF_STREAM: &str = "end";
+/// Header carrying the message of an error reply, matching the NATS services API
+pub const ERROR_HEADER: &str = "Nats-Service-Error";
+/// Header carrying the code of an error reply, matching the NATS services API
+pub const ERROR_CODE_HEADER: &str = "Nats-Service-Error-Code";
+/// Header carrying the position of a chunk of a payload too large to send as one message,
+/// starting at 0
+pub const CHUNK_HEADER: &str = "Nats-Rpc-Chunk";
+/// Header carrying the number of chunks a payload too large to send as one message was split
+/// into
+pub const CHUNKS_HEADER: &str = "Nats-Rpc-Chunks";
+/// Largest message a [Transport] accepts unless it says otherwise, matching the default
+/// `max_payload` of a NATS server
+pub const DEFAULT_MAX_PAYLOAD: usize = 1024 * 1024;
+/// Room left in each chunk for the headers added when splitting a payload
+const CHUNKING_OVERHEAD: usize = 256;
+
+/// An error returned by a service, sent to the caller as an error reply.
+///
+/// Generated clients return this error, which can be recovered with
+/// [anyhow::Error::downcast_ref], when the service replies with an error. Handlers can return
+/// it to choose the code and details the caller receives. Any other handler error is sent with
+/// the code `500` and the error chain as its message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteError {
+    /// Code describing the kind of error, following HTTP status codes by convention
+    pub code: u16,
+    /// Human readable description of the error
+    pub message: String,
+    /// Optional details about the error, sent as the payload of the error reply
+    pub details: Option<String>,
+}
+
+impl RemoteError {
+    /// Create an error with the given code and message
+    pub fn new(code: u16, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// Attach details to the error
+    pub fn with_details(mut self, details: impl Into<String>) -> Self {
+        self.details = Some(details.into());
+        self
+    }
+
+    /// Read an error reply, returning `None` if the message isn't one
+    pub fn from_message(message: &async_nats::Message) -> Option<Self> {
+        let error = header(message, ERROR_HEADER)?;
+        let code = header(message, ERROR_CODE_HEADER)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(500);
+        let details = (!message.payload.is_empty())
+            .then(|| String::from_utf8_lossy(&message.payload).into_owned());
+        Some(Self {
+            code,
+            message: error.to_string(),
+            details,
+        })
+    }
+
+    fn headers(&self, mut headers: async_nats::HeaderMap) -> async_nats::HeaderMap {
+        headers.insert(ERROR_HEADER, self.message.as_str());
+        headers.insert(ERROR_CODE_HEADER, self.code.to_string().as_str());
+        headers
+    }
+}
+
+impl From<&anyhow::Error> for RemoteError {
+    fn from(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<RemoteError>()
+            .cloned()
+            .unwrap_or_else(|| RemoteError::new(500, format!("{error:#}")))
+    }
+}
+
+impl std::fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "service returned error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for RemoteError {}
+
+/// Time a server waits for each request of a client or bidirectional streaming call unless
+/// the server's `stream_idle_timeout` says otherwise
+pub const STREAM_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Time a [Transport] waits for a reply when a request doesn't set a timeout, matching the
+/// default request timeout of [async_nats::Client]
+pub const DEFAULT_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The messaging operations generated clients and servers are built on.
+///
+/// It's implemented for [async_nats::Client], and can be implemented to run services over
+/// another transport, or to instrument the calls they make.
+pub trait Transport: Clone + Send + Sync + 'static {
+    /// Stream of the messages received by a subscription, ending when it's closed
+    type Subscriber: futures::Stream<Item = async_nats::Message> + Send + Unpin + 'static;
+
+    /// Send a request to `subject` and wait for a single reply, with the headers, timeout and
+    /// inbox set in `options`
+    fn request(
+        &self,
+        subject: String,
+        payload: bytes::Bytes,
+        options: CallOptions,
+    ) -> impl std::future::Future<Output = anyhow::Result<async_nats::Message>> + Send;
+
+    /// Publish a message to `subject`, asking for replies on `reply` if it's set
+    fn publish(
+        &self,
+        subject: String,
+        reply: Option<String>,
+        headers: async_nats::HeaderMap,
+        payload: bytes::Bytes,
+    ) -> impl std::future::Future<Output = anyhow::Result<()>> + Send;
+
+    /// Subscribe to `subject`, which may contain wildcards, joining `queue_group` if one is
+    /// given so that each message is delivered to only one member of the group
+    fn subscribe(
+        &self,
+        subject: String,
+        queue_group: Option<String>,
+    ) -> impl std::future::Future<Output = anyhow::Result<Self::Subscriber>> + Send;
+
+    /// Create a unique subject to receive replies on
+    fn new_inbox(&self) -> String;
+
+    /// Largest message, headers included, the transport can publish. Larger payloads are split
+    /// into chunks. Defaults to [DEFAULT_MAX_PAYLOAD].
+    fn max_payload(&self) -> usize {
+        DEFAULT_MAX_PAYLOAD
+    }
+}
+
+impl Transport for async_nats::Client {
+    type Subscriber = async_nats::Subscriber;
+
+    async fn request(
+        &self,
+        subject: String,
+        payload: bytes::Bytes,
+        options: CallOptions,
+    ) -> anyhow::Result<async_nats::Message> {
+        Ok(self
+            .send_request(subject, request(payload, options))
+            .await?)
+    }
+
+    async fn publish(
+        &self,
+        subject: String,
+        reply: Option<String>,
+        headers: async_nats::HeaderMap,
+        payload: bytes::Bytes,
+    ) -> anyhow::Result<()> {
+        match (reply, headers.is_empty()) {
+            (Some(reply), true) => self.publish_with_reply(subject, reply, payload).await?,
+            (Some(reply), false) => {
+                self.publish_with_reply_and_headers(subject, reply, headers, payload)
+                    .await?
+            }
+            (None, true) => async_nats::Client::publish(self, subject, payload).await?,
+            (None, false) => self.publish_with_headers(subject, headers, payload).await?,
+        }
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        subject: String,
+        queue_group: Option<String>,
+    ) -> anyhow::Result<Self::Subscriber> {
+        let subscriber = match queue_group {
+            Some(queue_group) => self.queue_subscribe(subject, queue_group).await?,
+            None => async_nats::Client::subscribe(self, subject).await?,
+        };
+        Ok(subscriber)
+    }
+
+    fn new_inbox(&self) -> String {
+        async_nats::Client::new_inbox(self)
+    }
+
+    fn max_payload(&self) -> usize {
+        match self.server_info().max_payload {
+            // The server hasn't told the client its limit yet
+            0 => DEFAULT_MAX_PAYLOAD,
+            max_payload => max_payload,
+        }
+    }
+}
+
+/// Options for a single call of a generated client, passed to its `*_with_options` functions.
+///
+/// A timeout that isn't set falls back to the `timeout_ms` option of the method, then to the
+/// default timeout of the client and the request timeout of the connection. Other options that
+/// aren't set fall back to the defaults of the client.
+#[derive(Clone, Debug, Default)]
+pub struct CallOptions {
+    /// How long to wait for a reply, or for a call sending a stream of requests to be
+    /// accepted. Streams of responses are received without a timeout.
+    pub timeout: Option<std::time::Duration>,
+    /// Headers sent with the request, or with the request opening a streaming call
+    pub headers: async_nats::HeaderMap,
+    /// Inbox that replies are sent to, instead of a new inbox of the connection
+    pub inbox: Option<String>,
+}
+
+impl CallOptions {
+    /// Create options that leave everything to the defaults
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait `timeout` for the reply
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send a header with the request
+    pub fn header(
+        mut self,
+        name: impl async_nats::header::IntoHeaderName,
+        value: impl async_nats::header::IntoHeaderValue,
+    ) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Receive replies on `inbox`
+    pub fn inbox(mut self, inbox: impl Into<String>) -> Self {
+        self.inbox = Some(inbox.into());
+        self
+    }
+
+    /// Fill the options that aren't set from `defaults`. Headers are merged, keeping the
+    /// values of these options for headers set in both.
+    pub fn with_defaults(mut self, defaults: &CallOptions) -> Self {
+        self.timeout = self.timeout.or(defaults.timeout);
+        self.inbox = self.inbox.or_else(|| defaults.inbox.clone());
+        for (name, values) in defaults.headers.iter() {
+            if self.headers.get(name.clone()).is_none() {
+                for value in values {
+                    self.headers.append(name.clone(), value.clone());
+                }
+            }
+        }
+        self
+    }
+
+    /// Wait `timeout` for the reply unless a timeout is already set
+    pub fn with_default_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.timeout = self.timeout.or(Some(timeout));
+        self
+    }
+}
+
+/// A decoded reply along with the headers it was sent with
+#[derive(Clone, Debug)]
+pub struct Reply<M> {
+    /// The decoded message
+    pub message: M,
+    /// Headers of the reply, empty if it had none
+    pub headers: async_nats::HeaderMap,
+}
+
+/// How a method of a service is exposed over NATS
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MethodKind {
+    /// Clients send requests that the server replies to
+    RequestReply,
+    /// The server publishes events that clients subscribe to
+    PubSub,
+    /// Requests are stored in a JetStream work-queue stream until a server handles them
+    WorkQueue,
+}
+
+/// Describes a generated service and its methods, so that they can be listed at runtime by
+/// gateways, permission tooling or tests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ServiceDescriptor {
+    /// Name of the service, e.g. `PersonService`
+    pub name: &'static str,
+    /// Fully qualified name of the service, e.g. `example.PersonService`
+    pub full_name: &'static str,
+    /// Proto package of the service, empty if it has none
+    pub package: &'static str,
+    /// Subject prefix the service uses unless it's overridden at runtime
+    pub subject_prefix: &'static str,
+    /// Methods of the service, in the order they're declared
+    pub methods: &'static [MethodDescriptor],
+}
+
+impl ServiceDescriptor {
+    /// Find a method by its proto name, e.g. `GetPerson`
+    pub fn method(&self, proto_name: &str) -> Option<&'static MethodDescriptor> {
+        self.methods
+            .iter()
+            .find(|method| method.proto_name == proto_name)
+    }
+}
+
+/// Describes a method of a generated service
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MethodDescriptor {
+    /// Name of the method in the proto file, e.g. `GetPerson`
+    pub proto_name: &'static str,
+    /// Name of the generated Rust function, e.g. `get_person`
+    pub function_name: &'static str,
+    /// Whether the method handles requests or publishes events
+    pub kind: MethodKind,
+    /// Subject of the method with the default prefix, and a `*` wildcard in place of each
+    /// token bound to a request field
+    pub subject: &'static str,
+    /// Subject of the method with the `{prefix}` and request field placeholders left in,
+    /// e.g. `{prefix}.orders.{tenant_id}.get`
+    pub subject_template: &'static str,
+    /// Fully qualified proto name of the request type, e.g. `example.GetPersonRequest`
+    pub input_type: &'static str,
+    /// Fully qualified proto name of the response type, e.g. `example.GetPersonResponse`
+    pub output_type: &'static str,
+    /// Whether the client sends a stream of requests
+    pub client_streaming: bool,
+    /// Whether the server sends a stream of responses
+    pub server_streaming: bool,
+}
+
+impl MethodDescriptor {
+    /// Get the subject of the method for a subject prefix chosen at runtime, with a `*`
+    /// wildcard in place of each token bound to a request field
+    pub fn subject_with_prefix(&self, prefix: &str) -> String {
+        let mut subject = self
+            .subject_template
+            .replace("{prefix}", prefix.trim_end_matches('.'));
+        while let Some(start) = subject.find('{') {
+            let end = subject[start..]
+                .find('}')
+                .map_or(subject.len(), |end| start + end + 1);
+            subject.replace_range(start..end, "*");
+        }
+        subject
+    }
+}
+
+/// Values of any type attached to a request, keyed by their type
+#[derive(Default)]
+pub struct Extensions {
+    values: std::collections::HashMap<std::any::TypeId, Box<dyn std::any::Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Attach a value, returning the value of the same type attached before
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(std::any::TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    /// Get the attached value of type `T`
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&std::any::TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Get the attached value of type `T` to change it
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.values
+            .get_mut(&std::any::TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Detach the value of type `T`
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.values
+            .remove(&std::any::TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+/// Headers a handler sends back with its response. Clones share the same headers.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseHeaders(std::sync::Arc<std::sync::Mutex<async_nats::HeaderMap>>);
+
+impl ResponseHeaders {
+    /// Set a header, replacing any value set before
+    pub fn insert(
+        &self,
+        name: impl async_nats::header::IntoHeaderName,
+        value: impl async_nats::header::IntoHeaderValue,
+    ) {
+        self.lock().insert(name, value);
+    }
+
+    /// Get the headers set so far
+    pub fn get(&self) -> async_nats::HeaderMap {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, async_nats::HeaderMap> {
+        // Headers stay usable if a handler panicked while setting one
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// The request being handled by a server, passed to every handler along with the decoded
+/// request. Streaming calls carry the context of the message that opened them.
+#[derive(Debug)]
+pub struct RequestContext {
+    /// Subject the request was received on
+    pub subject: String,
+    /// Subject the response is sent to, if the caller expects one
+    pub reply: Option<String>,
+    /// Headers the request was sent with, empty if it had none
+    pub headers: async_nats::HeaderMap,
+    /// Values attached to the request by the code handling it, such as the caller identity
+    /// established by an authentication check
+    pub extensions: Extensions,
+    response_headers: ResponseHeaders,
+}
+
+impl RequestContext {
+    /// Create the context of a request received as `message`
+    pub fn new(message: &async_nats::Message) -> Self {
+        Self {
+            subject: message.subject.to_string(),
+            reply: message.reply.as_ref().map(ToString::to_string),
+            headers: message.headers.clone().unwrap_or_default(),
+            extensions: Extensions::default(),
+            response_headers: ResponseHeaders::default(),
+        }
+    }
+
+    /// Get the value of a request header, if present
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+
+    /// Send a header with the response, or with every frame of a response stream. Headers set
+    /// after the handler returns aren't sent.
+    pub fn set_response_header(
+        &self,
+        name: impl async_nats::header::IntoHeaderName,
+        value: impl async_nats::header::IntoHeaderValue,
+    ) {
+        self.response_headers.insert(name, value);
+    }
+
+    /// Get the headers sent with the response
+    pub fn response_headers(&self) -> ResponseHeaders {
+        self.response_headers.clone()
+    }
+}
+
+/// Encode a protobuf message into a NATS payload
+pub fn encode<M: prost::Message>(message: &M) -> bytes::Bytes {
+    let mut buf = bytes::BytesMut::with_capacity(message.encoded_len());
+    message
+        .encode(&mut buf)
+        .expect("to encode without reaching capacity");
+    buf.freeze()
+}
+
+/// Get the value of a header on a message, if present
+pub fn header<'a>(message: &'a async_nats::Message, name: &str) -> Option<&'a str> {
+    message
+        .headers
+        .as_ref()?
+        .get(name)
+        .map(|value| value.as_str())
+}
+
+/// Decode a request for a server handler, returning a [RemoteError] with the code `400` if the
+/// payload isn't a valid `M`
+pub fn decode_request<M: prost::Message + Default>(payload: bytes::Bytes) -> anyhow::Result<M> {
+    M::decode(payload).map_err(|e| {
+        let message = format!("failed to decode request as {}", std::any::type_name::<M>());
+        RemoteError::new(400, message)
+            .with_details(e.to_string())
+            .into()
+    })
+}
+
+/// Convert a message field into a subject token, failing if it's empty or contains characters
+/// that can't be used in a subject token
+pub fn subject_token(value: impl std::fmt::Display) -> anyhow::Result<String> {
+    let token = value.to_string();
+    if token.is_empty()
+        || token.contains(|c: char| matches!(c, '.' | '*' | '>') || c.is_whitespace())
+    {
+        anyhow::bail!("`{token}` can't be used as a subject token");
+    }
+    Ok(token)
+}
+
+/// Convert a field of a received request into a subject token like [subject_token], returning
+/// a [RemoteError] with the code `400` if it can't be used in one
+pub fn received_subject_token(value: impl std::fmt::Display) -> anyhow::Result<String> {
+    subject_token(value).map_err(|e| RemoteError::new(400, e.to_string()).into())
+}
+
+/// Check that a request arrived on the subject its fields map to, returning a [RemoteError]
+/// with the code `400` if it didn't
+pub fn check_subject(received: &str, expected: &str) -> anyhow::Result<()> {
+    if received != expected {
+        let message = format!("request fields map to subject {expected}, not {received}");
+        return Err(RemoteError::new(400, message).into());
+    }
+    Ok(())
+}
+
+/// Decode a reply, returning a [RemoteError] if the service replied with an error
+pub fn decode_reply<M: prost::Message + Default>(
+    message: async_nats::Message,
+) -> anyhow::Result<Reply<M>> {
+    if let Some(error) = RemoteError::from_message(&message) {
+        return Err(error.into());
+    }
+    let decoded = M::decode(message.payload)
+        .with_context(|| format!("failed to decode reply as {}", std::any::type_name::<M>()))?;
+    Ok(Reply {
+        message: decoded,
+        headers: message.headers.unwrap_or_default(),
+    })
+}
+
+/// Publish an error reply to `subject`, along with `headers`
+pub async fn publish_error(
+    client: &impl Transport,
+    subject: String,
+    error: &anyhow::Error,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    let error = RemoteError::from(error);
+    let payload = error
+        .details
+        .clone()
+        .map(bytes::Bytes::from)
+        .unwrap_or_default();
+    client
+        .publish(subject, None, error.headers(headers), payload)
+        .await
+        .context("failed to publish error reply")
+}
+
+/// Publish the result of a handler to `subject`, as either a reply or an error reply, along
+/// with `headers`
+pub async fn publish_reply<M: prost::Message>(
+    client: &impl Transport,
+    subject: String,
+    reply: anyhow::Result<M>,
+    headers: async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    match reply {
+        Ok(reply) => client
+            .publish(subject, None, headers, encode(&reply))
+            .await
+            .context("failed to publish reply"),
+        Err(e) => publish_error(client, subject, &e, headers).await,
+    }
+}
+
+/// Subscribe a server to `subject`, joining `queue_group` if one is given so that each
+/// message is delivered to only one member of the group
+pub async fn subscribe<T: Transport>(
+    client: &T,
+    subject: String,
+    queue_group: Option<&str>,
+) -> anyhow::Result<T::Subscriber> {
+    client
+        .subscribe(subject, queue_group.map(ToString::to_string))
+        .await
+}
+
+/// Build an [async_nats::Request] for `payload` with the headers, timeout and inbox set in
+/// `options`
+pub fn request(payload: bytes::Bytes, options: CallOptions) -> async_nats::Request {
+    let mut request = async_nats::Request::new().payload(payload);
+    if !options.headers.is_empty() {
+        request = request.headers(options.headers);
+    }
+    if options.timeout.is_some() {
+        request = request.timeout(options.timeout);
+    }
+    if let Some(inbox) = options.inbox {
+        request = request.inbox(inbox);
+    }
+    request
+}
+
+/// Open a streaming call on `subject`, asking the server to send responses to `inbox`.
+/// The headers in `options` are sent along with the call, which waits for the timeout in
+/// `options` to be accepted. Returns the session subject that requests should be published to.
+pub async fn open_session(
+    client: &impl Transport,
+    subject: String,
+    inbox: &str,
+    options: &CallOptions,
+) -> anyhow::Result<String> {
+    let mut headers = options.headers.clone();
+    headers.insert(INBOX_HEADER, inbox);
+    let options = CallOptions {
+        timeout: options.timeout,
+        headers,
+        inbox: None,
+    };
+    let accepted = client
+        .request(subject, bytes::Bytes::new(), options)
+        .await
+        .context("failed to open streaming call")?;
+    if let Some(error) = RemoteError::from_message(&accepted) {
+        return Err(error.into());
+    }
+    header(&accepted, SESSION_HEADER)
+        .map(ToString::to_string)
+        .context("server did not return a session subject for streaming call")
+}
+
+/// Accept a streaming call opened with [open_session], subscribing to a new session subject
+/// for requests. Returns the client inbox that responses should be published to along with
+/// the subscription for requests.
+pub async fn accept_session<T: Transport>(
+    client: &T,
+    message: &async_nats::Message,
+) -> anyhow::Result<(String, T::Subscriber)> {
+    let reply_to = message
+        .reply
+        .clone()
+        .context("no reply subject found in streaming call")?
+        .to_string();
+    let inbox = header(message, INBOX_HEADER)
+        .context("no inbox header found in streaming call")?
+        .to_string();
+    let session = client.new_inbox();
+    let requests = client
+        .subscribe(session.clone(), None)
+        .await
+        .context("failed to subscribe to streaming call session")?;
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(SESSION_HEADER, session.as_str());
+    client
+        .publish(reply_to, None, headers, bytes::Bytes::new())
+        .await
+        .context("failed to accept streaming call")?;
+    Ok((inbox, requests))
+}
+
+/// Publish a stream of messages to `subject` as sequenced frames carrying `headers`, followed
+/// by an end-of-stream marker carrying the number of frames sent
+pub async fn publish_frames<M: prost::Message + Send>(
+    client: &impl Transport,
+    subject: String,
+    messages: impl futures::Stream<Item = M> + Send,
+    headers: &async_nats::HeaderMap,
+) -> anyhow::Result<()> {
+    let sequence = publish_each_frame(client, &subject, messages, headers).await?;
+    publish_end_of_stream(client, subject, headers, sequence).await
+}
+
+/// Publish the responses of a bidirectional call like [publish_frames], ending them with an
+/// error reply instead of the end-of-stream marker if the call's request stream broke
+pub async fn publish_responses<M: prost::Message + Send>(
+    client: &impl Transport,
+    subject: String,
+    messages: impl futures::Stream<Item = M> + Send,
+    headers: &async_nats::HeaderMap,
+    requests: &RequestStreamStatus,
+) -> anyhow::Result<()> {
+    let sequence = publish_each_frame(client, &subject, messages, headers).await?;
+    match requests.check() {
+        Ok(()) => publish_end_of_stream(client, subject, headers, sequence).await,
+        Err(e) => publish_error(client, subject, &e, headers.clone()).await,
+    }
+}
+
+/// Publish each message as a frame, returning the number of frames published
+async fn publish_each_frame<M: prost::Message + Send>(
+    client: &impl Transport,
+    subject: &str,
+    messages: impl futures::Stream<Item = M> + Send,
+    headers: &async_nats::HeaderMap,
+) -> anyhow::Result<u64> {
+    futures::pin_mut!(messages);
+    let mut sequence = 0u64;
+    while let Some(message) = messages.next().await {
+        let frame_headers = frame_headers(headers, sequence);
+        // Frames too large for one message are published as consecutive chunks
+        let chunks = split_payload(client.max_payload(), &frame_headers, encode(&message))?;
+        let total = chunks.len();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            let headers = if total == 1 {
+                frame_headers.clone()
+            } else {
+                chunk_headers(&frame_headers, index, total)
+            };
+            client
+                .publish(subject.to_string(), None, headers, chunk)
+                .await
+                .context("failed to publish stream frame")?;
+        }
+        sequence += 1;
+    }
+    Ok(sequence)
+}
+
+async fn publish_end_of_stream(
+    client: &impl Transport,
+    subject: String,
+    headers: &async_nats::HeaderMap,
+    sequence: u64,
+) -> anyhow::Result<()> {
+    let mut headers = frame_headers(headers, sequence);
+    headers.insert(STATUS_HEADER, END_OF_STREAM);
+    client
+        .publish(subject, None, headers, bytes::Bytes::new())
+        .await
+        .context("failed to publish end of stream")
+}
+
+fn frame_headers(headers: &async_nats::HeaderMap, sequence: u64) -> async_nats::HeaderMap {
+    let mut headers = headers.clone();
+    headers.insert(SEQUENCE_HEADER, sequence.to_string().as_str());
+    headers
+}
+
+/// Decode frames published with [publish_frames], along with their headers, ending the stream
+/// at the end-of-stream marker. Frames published in chunks are joined before they're decoded.
+/// Error replies, frames or chunks that arrive out of order, or a stream that closes before
+/// the marker, produce a final error.
+pub fn decode_frames<M: prost::Message + Default + Send + 'static>(
+    messages: impl futures::Stream<Item = async_nats::Message> + Send + 'static,
+) -> impl futures::Stream<Item = anyhow::Result<Reply<M>>> + Send + 'static {
+    futures::stream::unfold(
+        (messages.boxed(), 0u64, false),
+        |(mut messages, sequence, done)| async move {
+            if done {
+                return None;
+            }
+            let mut chunks = Vec::new();
+            loop {
+                let Some(message) = messages.next().await else {
+                    let err = anyhow::anyhow!(
+                        "stream closed after {sequence} frames without end of stream"
+                    );
+                    return Some((Err(err), (messages, sequence, true)));
+                };
+                if let Some(error) = RemoteError::from_message(&message) {
+                    return Some((Err(error.into()), (messages, sequence, true)));
+                }
+                let received =
+                    header(&message, SEQUENCE_HEADER).and_then(|s| s.parse::<u64>().ok());
+                if received != Some(sequence) {
+                    let err =
+                        anyhow::anyhow!("expected stream frame {sequence}, received {received:?}");
+                    return Some((Err(err), (messages, sequence, true)));
+                }
+                if header(&message, STATUS_HEADER) == Some(END_OF_STREAM) {
+                    return None;
+                }
+                let message = match join_chunk(&mut chunks, message) {
+                    Ok(Some(message)) => message,
+                    // Wait for the rest of the frame
+                    Ok(None) => continue,
+                    Err(e) => return Some((Err(e), (messages, sequence, true))),
+                };
+                let decoded = M::decode(message.payload)
+                    .context("failed to decode stream frame")
+                    .map(|decoded| Reply {
+                        message: decoded,
+                        headers: message.headers.unwrap_or_default(),
+                    });
+                return Some((decoded, (messages, sequence + 1, false)));
+            }
+        },
+    )
+}
+
+/// Split `payload` into chunks that fit in messages of `max_payload` bytes along with
+/// `headers` and the chunk headers, or return it whole if it fits as it is
+fn split_payload(
+    max_payload: usize,
+    headers: &async_nats::HeaderMap,
+    payload: bytes::Bytes,
+) -> anyhow::Result<Vec<bytes::Bytes>> {
+    let headers_len = headers_len(headers);
+    if headers_len + payload.len() <= max_payload {
+        return Ok(vec![payload]);
+    }
+    let chunk_size = max_payload.saturating_sub(headers_len + CHUNKING_OVERHEAD);
+    if chunk_size == 0 {
+        anyhow::bail!("headers of {headers_len} bytes leave no room for the payload in a message of {max_payload} bytes");
+    }
+    Ok((0..payload.len())
+        .step_by(chunk_size)
+        .map(|start| payload.slice(start..payload.len().min(start + chunk_size)))
+        .collect())
+}
+
+/// Size of `headers` when they're sent with a message
+fn headers_len(headers: &async_nats::HeaderMap) -> usize {
+    if headers.is_empty() {
+        return 0;
+    }
+    // The `NATS/1.0` version line, a line for each value, and the blank line ending them
+    let lines = headers
+        .iter()
+        .map(|(name, values)| {
+            let name: &str = name.as_ref();
+            values
+                .iter()
+                .map(|value| name.len() + value.as_str().len() + 4)
+                .sum::<usize>()
+        })
+        .sum::<usize>();
+    10 + lines + 2
+}
+
+/// Add the headers of the chunk at `index` of a payload split into `total` chunks
+fn chunk_headers(
+    headers: &async_nats::HeaderMap,
+    index: usize,
+    total: usize,
+) -> async_nats::HeaderMap {
+    let mut headers = headers.clone();
+    headers.insert(CHUNK_HEADER, index.to_string().as_str());
+    headers.insert(CHUNKS_HEADER, total.to_string().as_str());
+    headers
+}
+
+/// Get the number of chunks a message's payload was split into, if it was
+fn chunk_count(message: &async_nats::Message) -> Option<usize> {
+    header(message, CHUNKS_HEADER)
+        .and_then(|total| total.parse().ok())
+        .filter(|&total| total > 1)
+}
+
+/// Remove the headers added when splitting a payload, along with `others`
+fn without_chunk_headers(headers: async_nats::HeaderMap, others: &[&str]) -> async_nats::HeaderMap {
+    let mut stripped = async_nats::HeaderMap::new();
+    for (name, values) in headers.iter() {
+        let name_str: &str = name.as_ref();
+        if [CHUNK_HEADER, CHUNKS_HEADER].contains(&name_str) || others.contains(&name_str) {
+            continue;
+        }
+        for value in values {
+            stripped.append(name.clone(), value.clone());
+        }
+    }
+    stripped
+}
+
+/// Join the payloads of consecutive chunks, collected in `chunks`, returning the message with
+/// the whole payload and the headers of its last chunk once the last chunk is received.
+/// Messages that weren't split are returned as they are.
+fn join_chunk(
+    chunks: &mut Vec<bytes::Bytes>,
+    mut message: async_nats::Message,
+) -> anyhow::Result<Option<async_nats::Message>> {
+    let Some(total) = chunk_count(&message) else {
+        return Ok(Some(message));
+    };
+    let index = header(&message, CHUNK_HEADER).and_then(|index| index.parse::<usize>().ok());
+    if index != Some(chunks.len()) {
+        anyhow::bail!(
+            "expected chunk {} of {total}, received {index:?}",
+            chunks.len()
+        );
+    }
+    chunks.push(std::mem::take(&mut message.payload));
+    if chunks.len() < total {
+        return Ok(None);
+    }
+    let mut payload = bytes::BytesMut::with_capacity(chunks.iter().map(bytes::Bytes::len).sum());
+    for chunk in chunks.drain(..) {
+        payload.extend_from_slice(&chunk);
+    }
+    message.payload = payload.freeze();
+    message.length = message.payload.len();
+    message.headers = message
+        .headers
+        .map(|headers| without_chunk_headers(headers, &[]));
+    Ok(Some(message))
+}
+
+/// Decode events published for a PubSub method
+pub fn decode_events<M: prost::Message + Default + Send + 'static>(
+    messages: impl futures::Stream<Item = async_nats::Message> + Send + 'static,
+) -> impl futures::Stream<Item = anyhow::Result<M>> + Send + 'static {
+    messages.map(|message| {
+        M::decode(message.payload)
+            .with_context(|| format!("failed to decode event as {}", std::any::type_name::<M>()))
+    })
+}
+
+/// Whether the request stream of a call decoded by [decode_requests] broke, holding the
+/// [RemoteError] it broke with
+#[derive(Clone, Debug, Default)]
+pub struct RequestStreamStatus {
+    error: std::sync::Arc<std::sync::Mutex<Option<RemoteError>>>,
+}
+
+impl RequestStreamStatus {
+    /// Return the error the request stream broke with, if it did. A handler that returns
+    /// before the stream ends can't have seen it break.
+    pub fn check(&self) -> anyhow::Result<()> {
+        match self.lock().clone() {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&self, error: RemoteError) {
+        self.lock().get_or_insert(error);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<RemoteError>> {
+        // The error is only ever set, so a poisoned lock is still usable
+        self.error
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Decode the requests of a streaming call for a server handler.
+///
+/// Frames that can't be received or decoded, and frames that arrive out of order, end the
+/// stream the handler receives and are recorded in the returned [RequestStreamStatus] as a
+/// [RemoteError] with the code `400`. Waiting longer than `idle_timeout` for a request ends it
+/// with the code `408`. Check it once the handler returns, so that the caller receives that
+/// error rather than a reply computed from part of its requests.
+pub fn decode_requests<M: prost::Message + Default + Send + 'static>(
+    messages: impl futures::Stream<Item = async_nats::Message> + Send + 'static,
+    idle_timeout: std::time::Duration,
+) -> (futures::stream::BoxStream<'static, M>, RequestStreamStatus) {
+    let status = RequestStreamStatus::default();
+    let frames = decode_frames::<M>(messages).boxed();
+    let requests = futures::stream::unfold(
+        (frames, status.clone()),
+        move |(mut frames, status)| async move {
+            let error = match tokio::time::timeout(idle_timeout, frames.next()).await {
+                Ok(Some(Ok(request))) => return Some((request.message, (frames, status))),
+                Ok(None) => return None,
+                Ok(Some(Err(e))) => RemoteError::new(400, format!("request stream broke: {e:#}")),
+                Err(_) => {
+                    RemoteError::new(408, format!("no request received for {idle_timeout:?}"))
+                }
+            };
+            status.fail(error);
+            None
+        },
+    )
+    .boxed();
+    (requests, status)
+}
+
+/// Drive a bidirectional call over a session opened with [open_session]: requests are
+/// published to the session subject while responses are decoded from `responses`. The
+/// returned stream ends when the server ends its response stream.
+pub fn call_bidirectional<Req, Resp>(
+    client: impl Transport,
+    session: String,
+    requests: impl futures::Stream<Item = Req> + Send + 'static,
+    responses: impl futures::Stream<Item = async_nats::Message> + Send + 'static,
+) -> futures::stream::BoxStream<'static, anyhow::Result<Reply<Resp>>>
+where
+    Req: prost::Message + Send + 'static,
+    Resp: prost::Message + Default + Send + 'static,
+{
+    enum Event<T> {
+        Response(anyhow::Result<T>),
+        Published(anyhow::Result<()>),
+        End,
+    }
+    let responses = decode_frames::<Resp>(responses)
+        .map(Event::Response)
+        .chain(futures::stream::once(futures::future::ready(Event::End)));
+    let published = futures::stream::once(async move {
+        publish_frames(&client, session, requests, &async_nats::HeaderMap::new()).await
+    })
+    .map(Event::Published);
+    futures::stream::select(responses, published)
+        .take_while(|event| futures::future::ready(!matches!(event, Event::End)))
+        .filter_map(|event| {
+            futures::future::ready(match event {
+                Event::Response(response) => Some(response),
+                Event::Published(Err(e)) => Some(Err(e)),
+                Event::Published(Ok(())) | Event::End => None,
+            })
+        })
+        .boxed()
+}
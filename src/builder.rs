@@ -0,0 +1,271 @@
+use std::path::PathBuf;
+
+use crate::subject::{validate_placeholders, validate_subject_template};
+use crate::{NatsServiceGenerator, SubjectStrategy};
+
+/// The default subject prefix used by generated clients and servers
+pub const DEFAULT_SUBJECT_PREFIX: &str = "nats.proto";
+
+/// The default number of requests a generated server handles at once
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 64;
+
+/// The name of the runtime module emitted into each generated package
+pub const DEFAULT_RUNTIME_MODULE: &str = "nats_rpc";
+
+/// Paths used by generated code to refer to its runtime dependencies
+#[derive(Clone, Debug)]
+pub(crate) struct RuntimePaths {
+    pub(crate) anyhow: String,
+    pub(crate) async_nats: String,
+    pub(crate) bytes: String,
+    pub(crate) futures: String,
+    pub(crate) prost: String,
+    pub(crate) serde_json: String,
+    pub(crate) tokio: String,
+    pub(crate) runtime: String,
+}
+
+impl Default for RuntimePaths {
+    fn default() -> Self {
+        Self {
+            anyhow: "::anyhow".to_string(),
+            async_nats: "::async_nats".to_string(),
+            bytes: "::bytes".to_string(),
+            futures: "::futures".to_string(),
+            prost: "::prost".to_string(),
+            serde_json: "::serde_json".to_string(),
+            tokio: "::tokio".to_string(),
+            runtime: DEFAULT_RUNTIME_MODULE.to_string(),
+        }
+    }
+}
+
+/// Builder for a configured [NatsServiceGenerator]
+///
+/// # Usage
+/// ```rust
+/// use protobuf_nats_service_generator::{NatsServiceGenerator, SubjectStrategy};
+///
+/// let generator = NatsServiceGenerator::builder()
+///     .subject_prefix("my.prefix")
+///     .subject_strategy(SubjectStrategy::SnakeCase)
+///     .generate_server(false)
+///     .build();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct NatsServiceGeneratorBuilder {
+    generator: NatsServiceGenerator,
+}
+
+impl NatsServiceGeneratorBuilder {
+    /// Set the default subject prefix emitted in the generated client and server traits.
+    /// Defaults to [DEFAULT_SUBJECT_PREFIX].
+    pub fn subject_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.generator.subject_prefix = prefix.into();
+        self
+    }
+
+    /// Whether to also implement the generated `{name}Client` trait directly on
+    /// `async_nats::Client`, calling the service under the default prefix. The `{name}NatsClient`
+    /// struct implements it either way, and can choose its own prefix. Defaults to `true` while
+    /// the deprecated `auto_subject_prefix` feature of this crate is enabled, as it is by
+    /// default, and to `false` once the feature is removed in the next release.
+    pub fn auto_subject_prefix(mut self, enabled: bool) -> Self {
+        self.generator.auto_subject_prefix = enabled;
+        self
+    }
+
+    /// Whether to generate the client trait and its `async_nats::Client` implementation.
+    /// Defaults to `true`.
+    pub fn generate_client(mut self, enabled: bool) -> Self {
+        self.generator.generate_client = enabled;
+        self
+    }
+
+    /// Whether to generate the server trait and its `start_server` entry point.
+    /// Defaults to `true`.
+    pub fn generate_server(mut self, enabled: bool) -> Self {
+        self.generator.generate_server = enabled;
+        self
+    }
+
+    /// Set the default number of requests a generated server handles at once, emitted in the
+    /// generated server traits. `None` handles every request as soon as it arrives, and `Some(1)`
+    /// handles requests one at a time. Defaults to [DEFAULT_MAX_CONCURRENT_REQUESTS].
+    pub fn max_concurrent_requests(mut self, limit: Option<usize>) -> Self {
+        self.generator.max_concurrent_requests = limit;
+        self
+    }
+
+    /// Set the strategy used to turn method names into subject tokens.
+    /// Defaults to [SubjectStrategy::DotCase].
+    pub fn subject_strategy(mut self, strategy: SubjectStrategy) -> Self {
+        self.generator.subject_strategy = strategy;
+        self
+    }
+
+    /// Set the template used to build the subject of each RPC. Defaults to
+    /// [DEFAULT_SUBJECT_TEMPLATE](crate::DEFAULT_SUBJECT_TEMPLATE).
+    ///
+    /// The following placeholders are supported:
+    /// - `{prefix}`: the runtime subject prefix, e.g. `nats.proto`
+    /// - `{package}`: the proto package, e.g. `example.v1`
+    /// - `{service}`: the snake_case service name, e.g. `person_service`
+    /// - `{method}`: the method name converted with the [SubjectStrategy], e.g. `get.person`
+    ///
+    /// Any other snake_case placeholder, such as `{tenant_id}`, is bound to the request field
+    /// of the same name. Clients fill it in from each request, and servers subscribe with a `*`
+    /// wildcard in its place and reject requests whose field doesn't match the subject. Request
+    /// fields can't be used in the subjects of client streaming methods.
+    ///
+    /// Use `"{prefix}.{method}"` for the subjects generated by previous versions of this crate.
+    ///
+    /// # Panics
+    /// Panics if the template does not contain `{method}` or contains a placeholder that is
+    /// neither supported nor a snake_case field name.
+    pub fn subject_template(mut self, template: impl Into<String>) -> Self {
+        let template = template.into();
+        validate_subject_template(&template);
+        self.generator.subject_template = template;
+        self
+    }
+
+    /// Override the subject of a single method, keyed by its fully qualified name such as
+    /// `example.PersonService.GetPerson`. Both the generated client and server use the override
+    /// in place of the subject template. It supports the same placeholders as
+    /// [subject_template](Self::subject_template), so `"legacy.people.get"` and
+    /// `"{prefix}.people.get"` are both valid. Takes precedence over a `subject` set in the
+    /// method's `nats.method` options.
+    ///
+    /// # Panics
+    /// Panics if the subject contains a placeholder that is neither supported nor a snake_case
+    /// field name.
+    pub fn method_subject(mut self, method: impl AsRef<str>, subject: impl Into<String>) -> Self {
+        let subject = subject.into();
+        validate_placeholders(&subject);
+        let method = method.as_ref().trim_start_matches('.').to_string();
+        self.generator.subject_overrides.insert(method, subject);
+        self
+    }
+
+    /// Whether to emit the `use` statements required by generated code at the top of each
+    /// generated file. Disable this when including several generated files in one module, and
+    /// import `anyhow::Context`, `futures::StreamExt` and `prost::Message` yourself.
+    /// Defaults to `true`.
+    pub fn emit_imports(mut self, enabled: bool) -> Self {
+        self.generator.emit_imports = enabled;
+        self
+    }
+
+    /// Set the path generated code uses for the `anyhow` crate. Defaults to `::anyhow`.
+    pub fn anyhow_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.anyhow = path.into();
+        self
+    }
+
+    /// Set the path generated code uses for the `async-nats` crate. Defaults to `::async_nats`.
+    pub fn async_nats_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.async_nats = path.into();
+        self
+    }
+
+    /// Set the path generated code uses for the `bytes` crate. Defaults to `::bytes`.
+    pub fn bytes_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.bytes = path.into();
+        self
+    }
+
+    /// Set the path generated code uses for the `futures` crate. Defaults to `::futures`.
+    pub fn futures_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.futures = path.into();
+        self
+    }
+
+    /// Set the path generated code uses for the `prost` crate. Defaults to `::prost`.
+    pub fn prost_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.prost = path.into();
+        self
+    }
+
+    /// Set the path generated code uses for the `serde_json` crate, which reads the responses of
+    /// the JetStream API when `jetstream` is enabled. Defaults to `::serde_json`.
+    pub fn serde_json_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.serde_json = path.into();
+        self
+    }
+
+    /// Set the path generated code uses for the `tokio` crate, which requires the `time`
+    /// feature to time out streaming calls. Defaults to `::tokio`.
+    pub fn tokio_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.tokio = path.into();
+        self
+    }
+
+    /// Whether to emit `loopback::LoopbackTransport` into the runtime module, an in-memory
+    /// transport for running generated clients and servers in tests without a NATS server.
+    /// It requires `tokio` with the `time` feature. Defaults to `false`.
+    pub fn loopback_transport(mut self, enabled: bool) -> Self {
+        self.generator.emit_loopback = enabled;
+        self
+    }
+
+    /// Whether to generate a `start_{service}_micro` entry point alongside `start_{service}_server`,
+    /// which registers the service with the NATS services API so that it can be discovered with
+    /// `nats micro ls` and reports per-endpoint stats. The service's version, description and
+    /// metadata, and each endpoint's metadata, are read from the `nats/options.proto` options.
+    /// The stats count every request, and every error of a handler. Replies with headers or
+    /// error details, replies sent in chunks and the replies of streaming methods can't go
+    /// through the services API, so they're counted by publishing an extra, empty reply to an
+    /// inbox nobody listens on. It requires the `service` feature of `async-nats`. Defaults to
+    /// `false`.
+    pub fn micro_service(mut self, enabled: bool) -> Self {
+        self.generator.micro_service = enabled;
+        self
+    }
+
+    /// Whether to emit the `jetstream` runtime module, required to generate methods with the
+    /// `METHOD_KIND_WORK_QUEUE` kind or the `resumable` option. Clients store the requests of
+    /// work-queue methods in a JetStream stream, and a `start_{service}_work_queue` entry point
+    /// serves them from durable consumers. Servers store the responses of resumable methods in
+    /// JetStream, so clients can resume them after a reconnect. It requires `tokio` with the
+    /// `rt` and `time` features, and `serde_json`. Defaults to `false`.
+    pub fn jetstream(mut self, enabled: bool) -> Self {
+        self.generator.jetstream = enabled;
+        self
+    }
+
+    /// Whether to emit the `chunks` runtime module and send the requests and replies of unary
+    /// and client streaming methods, and the requests of server streaming methods, through it,
+    /// so that payloads larger than the server's `max_payload` are split into chunks and joined
+    /// by the receiver. Stream frames are split into chunks whether or not this is enabled. It
+    /// requires `tokio` with the `time` feature. Work-queue and resumable methods store their
+    /// messages in JetStream as they are, so services with them can't be generated with
+    /// chunking. Defaults to `false`.
+    pub fn chunking(mut self, enabled: bool) -> Self {
+        self.generator.chunking = enabled;
+        self
+    }
+
+    /// Read the `nats/options.proto` options of services and methods from the encoded
+    /// `FileDescriptorSet` at `path`. Pass the same path to
+    /// `prost_build::Config::file_descriptor_set_path` so that it's written before services
+    /// are generated. See [OPTIONS_PROTO](crate::OPTIONS_PROTO) for the supported options.
+    pub fn file_descriptor_set_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.generator.file_descriptor_set_path = Some(path.into());
+        self
+    }
+
+    /// Set the path of an existing runtime module for generated code to use, instead of
+    /// emitting a [DEFAULT_RUNTIME_MODULE] module into each generated package. The module can
+    /// be created from [NatsServiceGenerator::runtime_module].
+    pub fn runtime_path(mut self, path: impl Into<String>) -> Self {
+        self.generator.paths.runtime = path.into();
+        self.generator.emit_runtime = false;
+        self
+    }
+
+    /// Build the configured [NatsServiceGenerator]
+    pub fn build(self) -> NatsServiceGenerator {
+        self.generator
+    }
+}
@@ -1,394 +1,1972 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 use convert_case::{Case, Casing};
 use prost_build::{Service, ServiceGenerator};
 
-pub struct NatsServiceGenerator;
+mod builder;
+mod options;
+mod subject;
+use builder::RuntimePaths;
+pub use builder::{
+    NatsServiceGeneratorBuilder, DEFAULT_MAX_CONCURRENT_REQUESTS, DEFAULT_RUNTIME_MODULE,
+    DEFAULT_SUBJECT_PREFIX,
+};
+pub use options::OPTIONS_PROTO;
+use options::{qualified_name, MethodKind, MethodOptions, ProtoOptions, ServiceOptions};
+use subject::{
+    field_placeholders, resolve_subject_template, subject_expr, validate_placeholders, FieldTokens,
+};
+pub use subject::{SubjectStrategy, DEFAULT_SUBJECT_TEMPLATE};
 
-impl ServiceGenerator for NatsServiceGenerator {
-    fn generate(&mut self, service: Service, buf: &mut String) {
-        let client_handlers_trait = get_client_handlers_trait(&service);
-        let client_nats_implementation = get_client_nats_implementation(&service);
+/// A [ServiceGenerator] that generates NATS clients and servers for protobuf services
+///
+/// Use [NatsServiceGenerator::default] for the default configuration, or
+/// [NatsServiceGenerator::builder] to configure generation for a single `build.rs`.
+#[derive(Clone, Debug)]
+pub struct NatsServiceGenerator {
+    pub(crate) subject_prefix: String,
+    pub(crate) auto_subject_prefix: bool,
+    pub(crate) generate_client: bool,
+    pub(crate) generate_server: bool,
+    pub(crate) max_concurrent_requests: Option<usize>,
+    pub(crate) subject_strategy: SubjectStrategy,
+    pub(crate) subject_template: String,
+    pub(crate) emit_imports: bool,
+    pub(crate) emit_runtime: bool,
+    pub(crate) emit_loopback: bool,
+    pub(crate) micro_service: bool,
+    pub(crate) jetstream: bool,
+    pub(crate) chunking: bool,
+    pub(crate) paths: RuntimePaths,
+    pub(crate) file_descriptor_set_path: Option<PathBuf>,
+    pub(crate) subject_overrides: HashMap<String, String>,
+    proto_options: Option<ProtoOptions>,
+}
 
-        let server_handlers_trait = get_server_handlers_trait(&service);
-        let server_nats_implementation = get_server_nats_implementation(&service);
+impl Default for NatsServiceGenerator {
+    fn default() -> Self {
+        Self {
+            subject_prefix: DEFAULT_SUBJECT_PREFIX.to_string(),
+            // The deprecated feature keeps its old meaning until it's removed
+            auto_subject_prefix: cfg!(feature = "auto_subject_prefix"),
+            generate_client: true,
+            generate_server: true,
+            max_concurrent_requests: Some(DEFAULT_MAX_CONCURRENT_REQUESTS),
+            subject_strategy: SubjectStrategy::default(),
+            subject_template: DEFAULT_SUBJECT_TEMPLATE.to_string(),
+            emit_imports: true,
+            emit_runtime: true,
+            emit_loopback: false,
+            micro_service: false,
+            jetstream: false,
+            chunking: false,
+            paths: RuntimePaths::default(),
+            file_descriptor_set_path: None,
+            subject_overrides: HashMap::new(),
+            proto_options: None,
+        }
+    }
+}
+
+impl NatsServiceGenerator {
+    /// Create a [NatsServiceGeneratorBuilder] to configure code generation
+    pub fn builder() -> NatsServiceGeneratorBuilder {
+        NatsServiceGeneratorBuilder::default()
+    }
 
-        let code = format!(
+    /// Render the runtime support code used by generated services, such as the framing
+    /// protocol for streaming calls.
+    ///
+    /// By default this code is emitted into a `nats_rpc` module in each generated package.
+    /// When several packages are generated into one crate, write it to a file once and point
+    /// every generator at it with [NatsServiceGeneratorBuilder::runtime_path] instead.
+    ///
+    /// # Usage
+    /// ```rust,no_run
+    /// # use protobuf_nats_service_generator::NatsServiceGenerator;
+    /// let generator = NatsServiceGenerator::builder()
+    ///     .runtime_path("crate::nats_rpc")
+    ///     .build();
+    /// let out_dir = std::env::var("OUT_DIR").unwrap();
+    /// std::fs::write(format!("{out_dir}/nats_rpc.rs"), generator.runtime_module()).unwrap();
+    /// // Then, in your crate root:
+    /// // pub mod nats_rpc { include!(concat!(env!("OUT_DIR"), "/nats_rpc.rs")); }
+    /// ```
+    pub fn runtime_module(&self) -> String {
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            bytes,
+            futures,
+            prost,
+            serde_json,
+            tokio,
+            ..
+        } = &self.paths;
+        let loopback = if self.emit_loopback {
+            format!(
+                r#"
+                /// In-memory transport for testing generated services without a NATS server
+                pub mod loopback {{
+                    #[allow(unused_imports)]
+                    use {tokio} as tokio;
+                    {LOOPBACK_SOURCE}
+                }}
+                "#
+            )
+        } else {
+            String::new()
+        };
+        let micro = if self.micro_service {
+            format!(
+                r#"
+                /// Support for serving generated services through the NATS services API
+                pub mod micro {{
+                    {MICRO_SOURCE}
+                }}
+                "#
+            )
+        } else {
+            String::new()
+        };
+        let jetstream = if self.jetstream {
+            format!(
+                r#"
+                /// Support for work-queue methods, whose requests are stored in JetStream
+                pub mod jetstream {{
+                    #[allow(unused_imports)]
+                    use {tokio} as tokio;
+                    use {serde_json} as serde_json;
+                    {JETSTREAM_SOURCE}
+                }}
+                "#
+            )
+        } else {
+            String::new()
+        };
+        let chunks = if self.chunking {
+            format!(
+                r#"
+                /// Support for requests and replies too large to send as one message
+                pub mod chunks {{
+                    #[allow(unused_imports)]
+                    use {tokio} as tokio;
+                    {CHUNKS_SOURCE}
+                }}
+                "#
+            )
+        } else {
+            String::new()
+        };
+        format!(
             r#"
+            #[allow(unused_imports)]
+            use {anyhow} as anyhow;
+            #[allow(unused_imports)]
+            use {async_nats} as async_nats;
+            #[allow(unused_imports)]
+            use {bytes} as bytes;
+            #[allow(unused_imports)]
+            use {futures} as futures;
+            #[allow(unused_imports)]
+            use {prost} as prost;
+            #[allow(unused_imports)]
+            use {tokio} as tokio;
+            {RUNTIME_SOURCE}
+            {loopback}
+            {micro}
+            {jetstream}
+            {chunks}
+            "#
+        )
+    }
+}
+
+/// Source of the runtime module emitted alongside generated services
+const RUNTIME_SOURCE: &str = include_str!("../runtime/nats_rpc.rs");
+/// Source of the in-memory transport emitted into the runtime module when enabled
+const LOOPBACK_SOURCE: &str = include_str!("../runtime/loopback.rs");
+/// Source of the NATS services API support emitted into the runtime module when enabled
+const MICRO_SOURCE: &str = include_str!("../runtime/micro.rs");
+/// Source of the work-queue support emitted into the runtime module when enabled
+const JETSTREAM_SOURCE: &str = include_str!("../runtime/jetstream.rs");
+/// Source of the chunking support emitted into the runtime module when enabled
+const CHUNKS_SOURCE: &str = include_str!("../runtime/chunks.rs");
+
+impl ServiceGenerator for NatsServiceGenerator {
+    fn generate(&mut self, service: Service, buf: &mut String) {
+        // protoc has written the descriptor set by the time services are generated
+        if self.proto_options.is_none() {
+            if let Some(path) = &self.file_descriptor_set_path {
+                self.proto_options = Some(ProtoOptions::load(path));
+            }
+        }
+        buf.push_str(&self.get_service_descriptor(&service));
+
+        if self.generate_client {
+            let client_handlers_trait = self.get_client_handlers_trait(&service);
+            let client_nats_implementation = self.get_client_nats_implementation(&service);
+            let client_struct = self.get_client_struct(&service);
+            buf.push_str(&format!(
+                r#"
             // Client handlers
             {client_handlers_trait}
             {client_nats_implementation}
+            {client_struct}
+            "#
+            ));
+        }
+
+        if self.generate_server {
+            let server_handlers_trait = self.get_server_handlers_trait(&service);
+            let server_nats_implementation = self.get_server_nats_implementation(&service);
+            let server_micro_implementation = self.get_server_micro_implementation(&service);
+            let server_work_queue_implementation =
+                self.get_server_work_queue_implementation(&service);
+            let server_publisher = self.get_server_publisher(&service);
+            buf.push_str(&format!(
+                r#"
             // Server handlers
             {server_handlers_trait}
             {server_nats_implementation}
+            {server_micro_implementation}
+            {server_work_queue_implementation}
+            {server_publisher}
             "#
-        );
-        buf.push_str(&code);
+            ));
+        }
     }
 
-    fn finalize(&mut self, _buf: &mut String) {
-        const IMPORTS_CODE: &str = r#"
+    // Imports and the runtime module are emitted once per package rather than once per file,
+    // so packages split across several .proto files don't define them twice.
+    fn finalize_package(&mut self, _package: &str, buf: &mut String) {
+        let RuntimePaths {
+            anyhow,
+            futures,
+            prost,
+            runtime,
+            ..
+        } = &self.paths;
+        let imports = if self.emit_imports {
+            format!(
+                r#"use {anyhow}::Context as _;
+use {futures}::StreamExt;
+use {prost}::Message;
+"#
+            )
+        } else {
+            String::new()
+        };
+        let runtime_module = if self.emit_runtime {
+            format!(
+                r#"
+/// Runtime support for the NATS services in this file
+#[allow(dead_code)]
+pub mod {runtime} {{
+{}
+}}
+"#,
+                self.runtime_module()
+            )
+        } else {
+            String::new()
+        };
+        let header = format!(
+            r#"
 /// --------------------------------------------------------------
 /// This file was generated by the `protobuf-nats-service-generator` crate
 /// DO NOT MODIFY DIRECTLY
 /// --------------------------------------------------------------
-use ::anyhow::Context as _;
-use ::futures::StreamExt;
-use ::prost::Message;
-"#;
-        _buf.insert_str(0, IMPORTS_CODE);
+{imports}{runtime_module}"#
+        );
+        buf.insert_str(0, &header);
     }
 }
 
-/// Filter methods, returning methods of the provided [MethodType]
-fn filter_methods_by_type(
-    methods: &[prost_build::Method],
-    desired_type: MethodType,
-) -> Vec<&prost_build::Method> {
-    methods
-        .iter()
-        .filter(|&method| {
-            let method_type = get_method_type(method);
-            method_type == desired_type
-        })
-        .collect()
-}
+impl NatsServiceGenerator {
+    /// Get the `nats.method` options of a [Method](prost_build::Method), if any were set
+    fn method_options(
+        &self,
+        service: &Service,
+        method: &prost_build::Method,
+    ) -> Option<&MethodOptions> {
+        self.proto_options.as_ref()?.method(
+            &qualified_name(&service.package, &service.proto_name),
+            &method.proto_name,
+        )
+    }
 
-/// Generate function handlers for client implementations of a [Service]
-fn get_client_handlers_trait(service: &Service) -> String {
-    let name = &service.name;
+    /// Get the [MethodType] of a [Method](prost_build::Method) from its `nats.method` options,
+    /// falling back to its name when they don't set a kind
+    fn method_type(&self, service: &Service, method: &prost_build::Method) -> MethodType {
+        match self
+            .method_options(service, method)
+            .map(MethodOptions::kind)
+        {
+            Some(MethodKind::PubSub) => MethodType::PubSub,
+            Some(MethodKind::RequestReply) => MethodType::RequestResponse,
+            Some(MethodKind::WorkQueue) => {
+                let method_name = format!(
+                    "{}.{}",
+                    qualified_name(&service.package, &service.proto_name),
+                    method.proto_name
+                );
+                assert!(
+                    !method.client_streaming && !method.server_streaming,
+                    "{method_name} is a work-queue method, which can't stream requests or responses"
+                );
+                assert!(
+                    self.jetstream,
+                    "{method_name} is a work-queue method, enable NatsServiceGeneratorBuilder::jetstream to generate it"
+                );
+                // JetStream stores requests as they are, which would be lost in chunks
+                assert!(
+                    !self.chunking,
+                    "{method_name} is a work-queue method, whose requests can't be sent in chunks, disable NatsServiceGeneratorBuilder::chunking to generate it"
+                );
+                MethodType::WorkQueue
+            }
+            // Methods starting with subscribe are pubsub, everything else is request/response
+            _ if method.name.starts_with("subscribe") => MethodType::PubSub,
+            _ => MethodType::RequestResponse,
+        }
+    }
 
-    let methods = &service.methods;
-    let reply_methods = filter_methods_by_type(methods, MethodType::RequestResponse);
-    let function_handlers = reply_methods
-        .iter()
-        .map(|method| {
-            let function_name = convert_method_to_function(&method.name);
-            // TODO: Support client side streaming
-            let input_type = &method.input_type;
-            let output_type = if method.server_streaming {
-                format!("impl ::futures::Future<Output = ::anyhow::Result<::std::pin::Pin<::std::boxed::Box<impl ::futures::Stream<Item = {}>>>>>", method.output_type)
-            } else {
-                format!("impl ::futures::Future<Output = ::anyhow::Result<{}>>", method.output_type)
-            };
-            format!(
-                r#"
-                /// Send request [{input_type}], receiving the decoded [{output_type}]
-                #[allow(dead_code)]
-                fn {function_name}(
-                    &self,
-                    _request: {input_type},
-                ) -> {output_type} + Send;
+    /// Filter the methods of a [Service], returning methods of the provided [MethodType]
+    fn methods_of_type<'a>(
+        &self,
+        service: &'a Service,
+        desired_type: MethodType,
+    ) -> Vec<&'a prost_build::Method> {
+        service
+            .methods
+            .iter()
+            .filter(|&method| self.method_type(service, method) == desired_type)
+            .collect()
+    }
+
+    /// Whether the responses of a [Method](prost_build::Method) are stored in JetStream so that
+    /// clients can resume its stream, from its `nats.method` options
+    fn is_resumable(&self, service: &Service, method: &prost_build::Method) -> bool {
+        if !self
+            .method_options(service, method)
+            .is_some_and(|options| options.resumable)
+        {
+            return false;
+        }
+        let method_name = format!(
+            "{}.{}",
+            qualified_name(&service.package, &service.proto_name),
+            method.proto_name
+        );
+        assert!(
+            method.server_streaming && !method.client_streaming,
+            "{method_name} is resumable, which is only supported for server streaming methods"
+        );
+        assert!(
+            self.jetstream,
+            "{method_name} is resumable, enable NatsServiceGeneratorBuilder::jetstream to generate it"
+        );
+        assert!(
+            !self.chunking,
+            "{method_name} is resumable, whose requests and responses can't be sent in chunks, disable NatsServiceGeneratorBuilder::chunking to generate it"
+        );
+        true
+    }
+
+    /// Get the methods of a [Service] that clients send requests to, whether they're handled
+    /// over core NATS or through a work queue
+    fn request_methods<'a>(&self, service: &'a Service) -> Vec<&'a prost_build::Method> {
+        service
+            .methods
+            .iter()
+            .filter(|&method| self.method_type(service, method) != MethodType::PubSub)
+            .collect()
+    }
+
+    /// Get the `nats.service` options of a [Service], if any were set
+    fn service_options(&self, service: &Service) -> Option<&ServiceOptions> {
+        self.proto_options
+            .as_ref()?
+            .services
+            .get(&qualified_name(&service.package, &service.proto_name))
+    }
+
+    /// Get the queue group servers of a [Service] join by default, from its `nats.service`
+    /// options or its fully qualified name
+    fn queue_group(&self, service: &Service) -> Option<String> {
+        let configured = self
+            .service_options(service)
+            .and_then(|options| options.queue_group.clone());
+        match configured {
+            Some(queue_group) if queue_group.is_empty() => None,
+            Some(queue_group) => Some(queue_group),
+            None => Some(qualified_name(&service.package, &service.proto_name)),
+        }
+    }
+
+    /// Get the JetStream stream storing the requests of a [Service]'s work-queue methods, from
+    /// its `nats.service` options or its fully qualified name. It also names the stream storing
+    /// the responses of its resumable methods.
+    fn stream_name(&self, service: &Service) -> String {
+        self.service_options(service)
+            .and_then(|options| options.stream.clone())
+            .unwrap_or_else(|| {
+                qualified_name(&service.package, &service.proto_name).replace('.', "_")
+            })
+    }
+
+    /// Get the subject template of a [Method](prost_build::Method)
+    fn method_subject_template(&self, service: &Service, method: &prost_build::Method) -> &str {
+        // Overrides from the builder take precedence over the method's `nats.method` options
+        let method_name = format!(
+            "{}.{}",
+            qualified_name(&service.package, &service.proto_name),
+            method.proto_name
+        );
+        let proto_subject = self
+            .method_options(service, method)
+            .and_then(|o| o.subject.as_ref());
+        let template = match (self.subject_overrides.get(&method_name), proto_subject) {
+            (Some(subject), _) => subject,
+            (None, Some(subject)) => {
+                validate_placeholders(subject);
+                subject
+            }
+            (None, None) => &self.subject_template,
+        };
+        assert!(
+            !method.client_streaming || field_placeholders(template).is_empty(),
+            "the subject of {method_name} is bound to request fields, which isn't supported for client streaming methods"
+        );
+        template
+    }
+
+    /// Generate a Rust expression evaluating to the subject of a [Method](prost_build::Method),
+    /// using `prefix_expr` as the runtime subject prefix and rendering request fields as `fields`
+    fn method_subject_expr(
+        &self,
+        service: &Service,
+        method: &prost_build::Method,
+        prefix_expr: &str,
+        fields: FieldTokens,
+    ) -> String {
+        subject_expr(
+            self.method_subject_template(service, method),
+            prefix_expr,
+            fields,
+            &service.package,
+            &service.proto_name,
+            &self.subject_strategy.method_subject(&method.name),
+        )
+    }
+
+    /// Generate a constant describing a [Service] and each of its methods
+    fn get_service_descriptor(&self, service: &Service) -> String {
+        let RuntimePaths { runtime, .. } = &self.paths;
+        let name = &service.name;
+        let proto_name = &service.proto_name;
+        let package = &service.package;
+        let full_name = qualified_name(package, proto_name);
+        let subject_prefix = &self.subject_prefix;
+
+        let methods = service
+            .methods
+            .iter()
+            .map(|method| {
+                let proto_name = &method.proto_name;
+                let function_name = convert_method_to_function(&method.name);
+                let kind = match self.method_type(service, method) {
+                    MethodType::RequestResponse => "RequestReply",
+                    MethodType::PubSub => "PubSub",
+                    MethodType::WorkQueue => "WorkQueue",
+                };
+                let template = self.method_subject_template(service, method);
+                let subject_template = resolve_subject_template(
+                    template,
+                    package,
+                    &service.proto_name,
+                    &self.subject_strategy.method_subject(&method.name),
+                );
+                let mut subject =
+                    subject_template.replace("{prefix}", subject_prefix.trim_end_matches('.'));
+                for field in field_placeholders(template) {
+                    subject = subject.replace(&format!("{{{field}}}"), "*");
+                }
+                let input_type = method.input_proto_type.trim_start_matches('.');
+                let output_type = method.output_proto_type.trim_start_matches('.');
+                let client_streaming = method.client_streaming;
+                let server_streaming = method.server_streaming;
+                format!(
+                    r#"
+                    {runtime}::MethodDescriptor {{
+                        proto_name: "{proto_name}",
+                        function_name: "{function_name}",
+                        kind: {runtime}::MethodKind::{kind},
+                        subject: "{subject}",
+                        subject_template: "{subject_template}",
+                        input_type: "{input_type}",
+                        output_type: "{output_type}",
+                        client_streaming: {client_streaming},
+                        server_streaming: {server_streaming},
+                    }},
+                "#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        format!(
+            r#"
+            /// Describes {name} and each of its methods, including their subjects and types
+            #[allow(dead_code, non_upper_case_globals)]
+            pub const {name}Descriptor: {runtime}::ServiceDescriptor = {runtime}::ServiceDescriptor {{
+                name: "{proto_name}",
+                full_name: "{full_name}",
+                package: "{package}",
+                subject_prefix: "{subject_prefix}",
+                methods: &[
+                    {methods}
+                ],
+            }};
             "#
+        )
+    }
+
+    /// Generate function handlers for client implementations of a [Service]
+    fn get_client_handlers_trait(&self, service: &Service) -> String {
+        let RuntimePaths {
+            anyhow,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let subject_prefix = &self.subject_prefix;
+
+        let reply_methods = self.request_methods(service);
+        let function_handlers = reply_methods
+            .iter()
+            .map(|method| {
+                let function_name = convert_method_to_function(&method.name);
+                let method_input_type = &method.input_type;
+                let (input_type, request_doc) = if method.client_streaming {
+                    // Bidirectional calls keep sending requests while the response stream is polled
+                    let bounds = if method.server_streaming { "Send + 'static" } else { "Send" };
+                    (
+                        format!("impl {futures}::Stream<Item = {method_input_type}> + {bounds}"),
+                        format!("a stream of [{method_input_type}] requests"),
+                    )
+                } else {
+                    (method_input_type.to_string(), format!("request [{method_input_type}]"))
+                };
+                let future_type = |output_type: &str| if method.server_streaming {
+                    format!("impl {futures}::Future<Output = {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{output_type}>>>>>>")
+                } else {
+                    format!("impl {futures}::Future<Output = {anyhow}::Result<{output_type}>>")
+                };
+                let method_output_type = &method.output_type;
+                let output_type = future_type(method_output_type);
+                let reply_type = future_type(&format!("{runtime}::Reply<{method_output_type}>"));
+                format!(
+                    r#"
+                    /// Send {request_doc}, receiving the decoded [{method_output_type}]
+                    #[allow(dead_code)]
+                    fn {function_name}(
+                        &self,
+                        _request: {input_type},
+                    ) -> {output_type} + Send;
+
+                    /// Send {request_doc} with [{runtime}::CallOptions], receiving the decoded
+                    /// [{method_output_type}] along with the headers of the reply
+                    #[allow(dead_code)]
+                    fn {function_name}_with_options(
+                        &self,
+                        _request: {input_type},
+                        _options: {runtime}::CallOptions,
+                    ) -> {reply_type} + Send;
+                "#
+                )
+            })
+            .chain(
+                self.methods_of_type(service, MethodType::PubSub)
+                    .into_iter()
+                    .map(|method| {
+                        let function_name = convert_method_to_function(&method.name);
+                        let method_name = &method.proto_name;
+                        let output_type = &method.output_type;
+                        format!(
+                            r#"
+                    /// Subscribe to the [{output_type}] events published for {method_name}
+                    #[allow(dead_code)]
+                    fn {function_name}(
+                        &self,
+                    ) -> impl {futures}::Future<Output = {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{output_type}>>>>>> + Send;
+                "#
+                        )
+                    }),
             )
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    format!(
-        r#"
-        pub trait {name}ClientPrefix {{
-            /// Get the subject prefix for this service. Defaults to
-            /// "nats.proto" and can be overridden with your own implementation.
-            /// 
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        format!(
+            r#"
+            pub trait {name}ClientPrefix {{
+                /// Get the subject prefix for this service. Defaults to "{subject_prefix}".
+                ///
+                /// [{name}NatsClient] implements it with the prefix it was built with, which can
+                /// be chosen at runtime.
+                fn subject_prefix(&self) -> &str {{
+                    "{subject_prefix}"
+                }}
+            }}
+            /// This will be used to implement the handlers for the client
+            pub trait {name}Client {{
+                {function_handlers}
+            }}
+            "#,
+        )
+    }
+
+    /// Generate the [Service] client functions for a receiver, calling NATS through it
+    fn client_functions(&self, service: &Service, receiver: &ClientReceiver) -> String {
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            bytes,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let connection = receiver.connection;
+
+        let reply_methods = self.request_methods(service);
+        reply_methods
+            .iter()
+            .map(|method| {
+                let function_name = convert_method_to_function(&method.name);
+                // Fully qualified, as the client implements the prefix trait of every service
+                let prefix_expr = format!("<Self as {name}ClientPrefix>::subject_prefix(self)");
+                let request_fields = FieldTokens::Message {
+                    message: "request",
+                    runtime,
+                };
+                let subject =
+                    self.method_subject_expr(service, method, &prefix_expr, request_fields);
+                let input_type = &method.input_type;
+                let output_type = &method.output_type;
+                // Work-queue requests are stored in JetStream before a server replies to them
+                let send_request = if self.method_type(service, method) == MethodType::WorkQueue {
+                    format!("{runtime}::jetstream::request")
+                } else if self.chunking {
+                    format!("{runtime}::chunks::send_request")
+                } else {
+                    format!("{runtime}::Transport::request")
+                };
+                // Options of the call take precedence over the method's timeout, which takes
+                // precedence over the defaults of the receiver
+                let method_timeout = self
+                    .method_options(service, method)
+                    .and_then(|o| o.timeout_ms)
+                    .map(|timeout_ms| format!(".with_default_timeout(::std::time::Duration::from_millis({timeout_ms}))"))
+                    .unwrap_or_default();
+                let receiver_defaults = receiver
+                    .defaults
+                    .map(|defaults| format!(".with_defaults({defaults})"))
+                    .unwrap_or_default();
+                let options = if method_timeout.is_empty() && receiver_defaults.is_empty() {
+                    String::new()
+                } else {
+                    format!("let options = options{method_timeout}{receiver_defaults};")
+                };
+                let publish_request = if self.chunking {
+                    format!("{runtime}::chunks::publish_request")
+                } else {
+                    format!("{runtime}::Transport::publish")
+                };
+                // Replies too large for one message are sent in chunks
+                let receive_reply = if self.chunking {
+                    format!("let reply = {runtime}::chunks::receive_reply({connection}, reply).await?;")
+                } else {
+                    String::new()
+                };
+                let inbox = format!("let inbox = options.inbox.clone().unwrap_or_else(|| {runtime}::Transport::new_inbox({connection}));");
+                let default_options = format!("{runtime}::CallOptions::default()");
+                let receive_responses = if self.is_resumable(service, method) {
+                    let stream = self.stream_name(service);
+                    format!(
+                        r#"
+                        // Responses are stored in JetStream, so the stream resumes after a reconnect
+                        let sub = {runtime}::jetstream::call_resumable::<_, {output_type}>({connection}, {subject}, "{stream}", buf.into(), options)
+                            .await
+                            .context("failed to call {function_name}")?;
+                    "#
+                    )
+                } else {
+                    format!(
+                        r#"
+                        {inbox}
+                        let sub = {runtime}::Transport::subscribe({connection}, inbox.clone(), None).await?;
+                        {publish_request}({connection}, {subject}, Some(inbox), options.headers, buf.into())
+                            .await?;
+                        // The stream completes, unsubscribing from the inbox, once the server ends it
+                        let sub = {runtime}::decode_frames::<{output_type}>(sub);
+                    "#
+                    )
+                };
+                match (method.client_streaming, method.server_streaming) {
+                    (true, true) => format!(
+                        r#"
+                        /// Send a stream of [{input_type}] requests with [{runtime}::CallOptions], decode responses as a stream of [{output_type}] along with their headers
+                        async fn {function_name}_with_options(
+                            &self,
+                            requests: impl {futures}::Stream<Item = {input_type}> + Send + 'static,
+                            options: {runtime}::CallOptions,
+                        ) -> {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{runtime}::Reply<{output_type}>>>>>> {{
+                            {options}
+                            {inbox}
+                            let replies = {runtime}::Transport::subscribe({connection}, inbox.clone(), None)
+                                .await
+                                .context("failed to subscribe for {function_name} replies")?;
+                            let session = {runtime}::open_session({connection}, {subject}, &inbox, &options)
+                                .await
+                                .context("failed to open bidirectional stream for {function_name}")?;
+                            let sub = {runtime}::call_bidirectional(::std::clone::Clone::clone({connection}), session, requests, replies);
+
+                            Ok(Box::pin(sub))
+                        }}
+
+                        /// Send a stream of [{input_type}] requests, decode responses as a stream of [{output_type}]
+                        async fn {function_name}(
+                            &self,
+                            requests: impl {futures}::Stream<Item = {input_type}> + Send + 'static,
+                        ) -> {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{output_type}>>>>> {{
+                            let replies = {name}Client::{function_name}_with_options(self, requests, {default_options}).await?;
+
+                            Ok(Box::pin(replies.map(|reply| reply.map(|reply| reply.message))))
+                        }}
+                    "#),
+                    (true, false) => format!(
+                        r#"
+                        /// Send a stream of [{input_type}] requests with [{runtime}::CallOptions], decode response as [{output_type}] along with its headers
+                        async fn {function_name}_with_options(
+                            &self,
+                            requests: impl {futures}::Stream<Item = {input_type}> + Send,
+                            options: {runtime}::CallOptions,
+                        ) -> {anyhow}::Result<{runtime}::Reply<{output_type}>> {{
+                            {options}
+                            {inbox}
+                            let mut replies = {runtime}::Transport::subscribe({connection}, inbox.clone(), None)
+                                .await
+                                .context("failed to subscribe for {function_name} reply")?;
+                            let session = {runtime}::open_session({connection}, {subject}, &inbox, &options)
+                                .await
+                                .context("failed to open client stream for {function_name}")?;
+                            {runtime}::publish_frames({connection}, session, requests, &{async_nats}::HeaderMap::new()).await?;
+                            let reply = replies
+                                .next()
+                                .await
+                                .context("subscription closed before receiving reply for {function_name}")?;
+                            {receive_reply}
+                            {runtime}::decode_reply::<{output_type}>(reply)
+                        }}
+
+                        /// Send a stream of [{input_type}] requests, decode response as [{output_type}]
+                        async fn {function_name}(
+                            &self,
+                            requests: impl {futures}::Stream<Item = {input_type}> + Send,
+                        ) -> {anyhow}::Result<{output_type}> {{
+                            {name}Client::{function_name}_with_options(self, requests, {default_options})
+                                .await
+                                .map(|reply| reply.message)
+                        }}
+                    "#),
+                    (false, true) => format!(
+                        r#"
+                        /// Send request [{input_type}] with [{runtime}::CallOptions], decode responses as a stream of [{output_type}] along with their headers
+                        async fn {function_name}_with_options(
+                            &self,
+                            request: {input_type},
+                            options: {runtime}::CallOptions,
+                        ) -> {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{runtime}::Reply<{output_type}>>>>>> {{
+                            let mut buf = {bytes}::BytesMut::with_capacity(request.encoded_len());
+                            request
+                                .encode(&mut buf)
+                                .context("failed to encode {input_type}")?;
+                            {options}
+                            {receive_responses}
+
+                            Ok(Box::pin(sub))
+                        }}
+
+                        /// Send request [{input_type}], decode response as a stream of [{output_type}]
+                        async fn {function_name}(
+                            &self,
+                            request: {input_type},
+                        ) -> {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{output_type}>>>>> {{
+                            let replies = {name}Client::{function_name}_with_options(self, request, {default_options}).await?;
+
+                            Ok(Box::pin(replies.map(|reply| reply.map(|reply| reply.message))))
+                        }}
+                    "#),
+                    (false, false) => format!(
+                        r#"
+                        /// Send request [{input_type}] with [{runtime}::CallOptions], decode response as [{output_type}] along with its headers
+                        async fn {function_name}_with_options(
+                            &self,
+                            request: {input_type},
+                            options: {runtime}::CallOptions,
+                        ) -> {anyhow}::Result<{runtime}::Reply<{output_type}>> {{
+                            let mut buf = {bytes}::BytesMut::with_capacity(request.encoded_len());
+                            request
+                                .encode(&mut buf)
+                                .context("failed to encode {input_type}")?;
+                            {options}
+                            let reply = {send_request}({connection}, {subject}, buf.into(), options)
+                                .await
+                                .context("failed to send NATS request for {function_name}")?;
+                            {runtime}::decode_reply::<{output_type}>(reply)
+                        }}
+
+                        /// Send request [{input_type}], decode response as [{output_type}]
+                        async fn {function_name}(
+                            &self,
+                            request: {input_type},
+                        ) -> {anyhow}::Result<{output_type}> {{
+                            {name}Client::{function_name}_with_options(self, request, {default_options})
+                                .await
+                                .map(|reply| reply.message)
+                        }}
+                    "#),
+                }
+            })
+            .chain(
+                self.methods_of_type(service, MethodType::PubSub)
+                    .into_iter()
+                    .map(|method| {
+                        let function_name = convert_method_to_function(&method.name);
+                        let prefix_expr = format!("<Self as {name}ClientPrefix>::subject_prefix(self)");
+                        let subject = self.method_subject_expr(
+                            service,
+                            method,
+                            &prefix_expr,
+                            FieldTokens::Wildcard,
+                        );
+                        let output_type = &method.output_type;
+                        format!(
+                            r#"
+                        /// Subscribe to events, decoded as a stream of [{output_type}]
+                        async fn {function_name}(
+                            &self,
+                        ) -> {anyhow}::Result<::std::pin::Pin<::std::boxed::Box<impl {futures}::Stream<Item = {anyhow}::Result<{output_type}>>>>> {{
+                            let sub = {runtime}::Transport::subscribe({connection}, {subject}, None)
+                                .await
+                                .context("failed to subscribe for {function_name} events")?;
+
+                            Ok(Box::pin({runtime}::decode_events::<{output_type}>(sub)))
+                        }}
+                    "#
+                        )
+                    }),
+            )
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Implement the [Service] trait for an [async_nats::Client] with the default prefix, if
+    /// the `auto_subject_prefix` option asks for it
+    fn get_client_nats_implementation(&self, service: &Service) -> String {
+        if !self.auto_subject_prefix {
+            return String::new();
+        }
+        let async_nats = &self.paths.async_nats;
+        let name = &service.name;
+        let functions = self.client_functions(
+            service,
+            &ClientReceiver {
+                connection: "self",
+                defaults: None,
+            },
+        );
+
+        format!(
+            r#"
+            /// Call {name} directly on an async_nats::Client, under the default subject prefix.
+            /// Use [{name}NatsClient] to choose the prefix, timeout or headers of the calls.
+            impl {name}ClientPrefix for {async_nats}::Client {{}}
+
+            impl {name}Client for {async_nats}::Client {{
+                {functions}
+            }}
+            "#
+        )
+    }
+
+    /// Generate a client struct for a [Service], holding the connection along with the subject
+    /// prefix, timeout and headers used for every call
+    fn get_client_struct(&self, service: &Service) -> String {
+        let RuntimePaths {
+            async_nats,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let subject_prefix = &self.subject_prefix;
+        let functions = self.client_functions(
+            service,
+            &ClientReceiver {
+                connection: "self.client()",
+                defaults: Some("&self.options"),
+            },
+        );
+
+        format!(
+            r#"
+            /// A client for {name}, holding the connection along with the subject prefix,
+            /// timeout and headers used for every call
+            ///
             /// # Usage
-            /// For the default prefix, enable the `auto_subject_feature` or implement the trait as:
-            /// ```rust
-            /// impl {name}ClientPrefix for async_nats::Client {{}}
-            /// ```
-            /// 
-            /// To use your own prefix, implement this trait for your client:
-            /// ```rust
-            /// impl {name}ClientPrefix for async_nats::Client {{
-            ///     fn subject_prefix(&self) -> &'static str {{
-            ///        "my.prefix"
-            ///     }}
-            /// }}
+            /// ```ignore
+            /// let client = {name}NatsClient::builder(client)
+            ///     .subject_prefix("my.prefix")
+            ///     .timeout(std::time::Duration::from_secs(5))
+            ///     .build();
+            /// let response = client.function_name(request).await?;
             /// ```
-            fn subject_prefix(&self) -> &'static str {{
-                "nats.proto"
+            #[derive(Clone, Debug)]
+            pub struct {name}NatsClient<T = {async_nats}::Client> {{
+                client: T,
+                subject_prefix: ::std::sync::Arc<str>,
+                options: {runtime}::CallOptions,
             }}
-        }}
-        /// This will be used to implement the handlers for the client
-        pub trait {name}Client {{
-            {function_handlers}
-        }}
-        "#,
-    )
-}
 
-/// Implement the [Service] trait for an [async_nats::Client]
-fn get_client_nats_implementation(service: &Service) -> String {
-    let name = &service.name;
+            #[allow(dead_code)]
+            impl<T: {runtime}::Transport> {name}NatsClient<T> {{
+                /// Create a client using the default subject prefix, "{subject_prefix}", and the
+                /// request timeout of the connection
+                pub fn new(client: T) -> Self {{
+                    Self::builder(client).build()
+                }}
 
-    let methods = &service.methods;
-    let reply_methods = filter_methods_by_type(methods, MethodType::RequestResponse);
-    let functions = reply_methods
-        .iter()
-        .map(|method| {
-            let function_name = convert_method_to_function(&method.name);
-            let function_subject = convert_method_to_subject(&method.name);
-            // TODO: Support client side streaming
-            let input_type = &method.input_type;
-            if method.server_streaming {
-                format!(
+                /// Create a [{name}NatsClientBuilder] to configure the client
+                pub fn builder(client: T) -> {name}NatsClientBuilder<T> {{
+                    {name}NatsClientBuilder {{
+                        client: Self {{
+                            client,
+                            subject_prefix: "{subject_prefix}".into(),
+                            options: {runtime}::CallOptions::default(),
+                        }},
+                    }}
+                }}
+
+                /// Get the connection calls are made with
+                pub fn client(&self) -> &T {{
+                    &self.client
+                }}
+            }}
+
+            /// Builder for a configured [{name}NatsClient]
+            #[derive(Clone, Debug)]
+            pub struct {name}NatsClientBuilder<T = {async_nats}::Client> {{
+                client: {name}NatsClient<T>,
+            }}
+
+            #[allow(dead_code)]
+            impl<T: {runtime}::Transport> {name}NatsClientBuilder<T> {{
+                /// Call the service under a different subject prefix, matching the prefix of its servers
+                pub fn subject_prefix(mut self, subject_prefix: impl Into<::std::sync::Arc<str>>) -> Self {{
+                    self.client.subject_prefix = subject_prefix.into();
+                    self
+                }}
+
+                /// Wait `timeout` for replies rather than the request timeout of the connection.
+                /// Methods with a `timeout_ms` option keep their own timeout.
+                pub fn timeout(mut self, timeout: ::std::time::Duration) -> Self {{
+                    self.client.options.timeout = Some(timeout);
+                    self
+                }}
+
+                /// Send a header with every call
+                pub fn header(
+                    mut self,
+                    name: impl {async_nats}::header::IntoHeaderName,
+                    value: impl {async_nats}::header::IntoHeaderValue,
+                ) -> Self {{
+                    self.client.options.headers.insert(name, value);
+                    self
+                }}
+
+                /// Send `headers` with every call, replacing any headers set before
+                pub fn headers(mut self, headers: {async_nats}::HeaderMap) -> Self {{
+                    self.client.options.headers = headers;
+                    self
+                }}
+
+                /// Use `options` as the defaults of every call, replacing any set before.
+                /// The options passed to `*_with_options` functions take precedence.
+                pub fn options(mut self, options: {runtime}::CallOptions) -> Self {{
+                    self.client.options = options;
+                    self
+                }}
+
+                /// Build the configured [{name}NatsClient]
+                pub fn build(self) -> {name}NatsClient<T> {{
+                    self.client
+                }}
+            }}
+
+            impl<T: {runtime}::Transport> {name}ClientPrefix for {name}NatsClient<T> {{
+                fn subject_prefix(&self) -> &str {{
+                    &self.subject_prefix
+                }}
+            }}
+
+            impl<T: {runtime}::Transport> {name}Client for {name}NatsClient<T> {{
+                {functions}
+            }}
+            "#
+        )
+    }
+
+    /// Generate the trait for the handlers of a [Service]
+    fn get_server_handlers_trait(&self, service: &Service) -> String {
+        let RuntimePaths {
+            anyhow,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let subject_prefix = &self.subject_prefix;
+
+        let reply_methods = self.request_methods(service);
+        let function_handlers = reply_methods
+            .iter()
+            .map(|method| {
+                let function_name = convert_method_to_function(&method.name);
+                let method_name = &method.proto_name;
+                let input_type = if method.client_streaming {
+                    format!("{futures}::stream::BoxStream<'static, {}>", method.input_type)
+                } else {
+                    method.input_type.to_string()
+                };
+                let output_type = if method.server_streaming {
+                    format!("impl {futures}::Future<Output = {anyhow}::Result<impl {futures}::Stream<Item = {}> + Send>>", method.output_type)
+                } else {
+                    format!("impl {futures}::Future<Output = {anyhow}::Result<{}>>", method.output_type)
+                };
+                let handler = format!(
+                    r#"
+                    /// Implementation of {method_name}
+                    fn {function_name}(
+                        &self,
+                        _ctx: {runtime}::RequestContext,
+                        _request: {input_type},
+                    ) -> {output_type} + Send;
+                "#
+                );
+                let shared_handler = format!(
                     r#"
-                    /// Send request [{input_type}], decode response as a stream of [{output_type}]
-                    async fn {function_name}(
+                    fn {function_name}(
                         &self,
+                        ctx: {runtime}::RequestContext,
                         request: {input_type},
-                    ) -> ::anyhow::Result<::std::pin::Pin<::std::boxed::Box<impl ::futures::Stream<Item = {output_type}>>>> {{
-                        let mut buf = ::bytes::BytesMut::with_capacity(request.encoded_len());
-                        request
-                            .encode(&mut buf)
-                            .context("failed to encode {input_type}")?;
-                        let inbox = self.new_inbox();
-                        let sub = self.subscribe(inbox.clone()).await?;
-                        self
-                            .publish_with_reply(format!("{{}}.{function_subject}", self.subject_prefix().trim_end_matches('.')), inbox, buf.into())
-                            .await?;
-                        // TODO: provide error logging for failed decodes
-                        let sub = sub.filter_map(|msg| async {{ {output_type}::decode(msg.payload).ok() }});
-
-                        Ok(Box::pin(sub))
+                    ) -> {output_type} + Send {{
+                        T::{function_name}(self, ctx, request)
                     }}
-                "#, output_type = method.output_type)
-            } else {
+                "#
+                );
+                (handler, shared_handler)
+            })
+            .collect::<Vec<_>>();
+        let (function_handlers, shared_handlers): (Vec<_>, Vec<_>) =
+            function_handlers.into_iter().unzip();
+        let function_handlers = function_handlers.join("\n");
+        let shared_handlers = shared_handlers.join("\n");
+        let queue_group = match self.queue_group(service) {
+            Some(queue_group) => format!("Some(\"{queue_group}\")"),
+            None => "None".to_string(),
+        };
+        let max_concurrent_requests = match self.max_concurrent_requests {
+            Some(limit) => format!("Some({limit})"),
+            None => "None".to_string(),
+        };
+        // Only calls that stream their requests wait for them
+        let (stream_idle_timeout, shared_stream_idle_timeout) = if service
+            .methods
+            .iter()
+            .any(|method| method.client_streaming)
+        {
+            (
                 format!(
                     r#"
-                    /// Send request [{input_type}], decode response as [{output_type}]
-                    async fn {function_name}(
-                        &self,
-                        request: {input_type},
-                    ) -> anyhow::Result<{output_type}> {{
-                        let mut buf = ::bytes::BytesMut::with_capacity(request.encoded_len());
-                        request
-                            .encode(&mut buf)
-                            .context("failed to encode {input_type}")?;
-                        let reply = self
-                            .request(format!("{{}}.{function_subject}", self.subject_prefix().trim_end_matches('.')), buf.into())
-                            .await
-                            .context("failed to send NATS request for {function_name}")?;
-                        // TODO: more error handling on response message
-                        {output_type}::decode(reply.payload).context("failed to decode reply as {output_type}")
+                    /// Get how long a client or bidirectional streaming call waits for each of its
+                    /// requests before failing with a `408` error, so that calls the client abandoned
+                    /// don't keep holding one of the [{name}Server::max_concurrent_requests] slots.
+                    /// Defaults to [{runtime}::STREAM_IDLE_TIMEOUT].
+                    fn stream_idle_timeout(&self) -> ::std::time::Duration {{
+                        {runtime}::STREAM_IDLE_TIMEOUT
                     }}
-                "#, output_type = method.output_type)
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    // If the feature is enabled, generate the default implementation for the client prefix
-    #[cfg(feature = "auto_subject_prefix")]
-    let client_prefix_impl = format!("impl {name}ClientPrefix for ::async_nats::Client {{}}");
-    #[cfg(not(feature = "auto_subject_prefix"))]
-    let client_prefix_impl = "";
-
-    format!(
-        r#"
-        /// Implement the {name}Client trait for the async_nats::Client
-        /// 
-        /// # Usage
-        /// ```ignore
-        /// use generated::{{RequestType, ResponseType, {name}ClientPrefix}};
-        /// /// Define your subject prefix or use the default. The {name}Client trait is already
-        /// /// implemented for the async_nats::Client, so you can use it directly.
-        /// impl {name}ClientPrefix for async_nats::Client {{}}
-        /// async fn main() -> anyhow::Result<()> {{
-        ///    let client = async_nats::connect("nats://127.0.0.1:4222").await.expect("to connect");
-        ///    let request = RequestType::default();
-        ///    let response: ResponseType = client.function_name(request).await.expect("to send request");
-        ///    Ok(())
-        /// }}
-        /// 
-        /// ```
-        {client_prefix_impl}
-        impl {name}Client for ::async_nats::Client where ::async_nats::Client: {name}ClientPrefix {{
-            {functions}
-        }}
-        "#
-    )
-}
+                "#
+                ),
+                r#"
+                    fn stream_idle_timeout(&self) -> ::std::time::Duration {
+                        T::stream_idle_timeout(self)
+                    }
+                "#
+                .to_string(),
+            )
+        } else {
+            (String::new(), String::new())
+        };
+        // Requests are only joined from chunks with the `chunks` runtime module
+        let (max_request_size, shared_max_request_size) = if self.chunking {
+            (
+                format!(
+                    r#"
+                    /// Get the largest request, in bytes, that is joined from chunks before
+                    /// failing with a `413` error. Defaults to
+                    /// [{runtime}::chunks::MAX_CHUNKED_PAYLOAD].
+                    fn max_request_size(&self) -> usize {{
+                        {runtime}::chunks::MAX_CHUNKED_PAYLOAD
+                    }}
+                "#
+                ),
+                r#"
+                    fn max_request_size(&self) -> usize {
+                        T::max_request_size(self)
+                    }
+                "#
+                .to_string(),
+            )
+        } else {
+            (String::new(), String::new())
+        };
 
-/// Generate the trait for the handlers of a [Service]
-fn get_server_handlers_trait(service: &Service) -> String {
-    let name = &service.name;
+        format!(
+            r#"
+            /// This will be used to implement the handlers for the server
+            pub trait {name}Server {{
+                /// Get the subject prefix for this service. Defaults to
+                /// "{subject_prefix}" and can be overridden with your own implementation.
+                /// If the subject prefix does not include the trailing '.' character, it will be added.
+                fn subject_prefix(&self) -> &str {{
+                    "{subject_prefix}"
+                }}
+                /// Called when handling a message for `method` fails, after an error reply has
+                /// been sent to the caller where possible. Returning an error stops the server,
+                /// resolving its future with that error. By default the error is logged and the
+                /// server keeps handling messages.
+                fn on_error(&self, method: &str, error: {anyhow}::Error) -> {anyhow}::Result<()> {{
+                    eprintln!("failed to handle {{method}} message: {{error:#}}");
+                    Ok(())
+                }}
+                /// Get the number of requests to handle at once, or `None` to handle every
+                /// request as soon as it arrives. Defaults to {max_concurrent_requests}.
+                fn max_concurrent_requests(&self) -> Option<usize> {{
+                    {max_concurrent_requests}
+                }}
+                /// Get the queue group that servers for this service join, so that each request
+                /// is handled by one server when several are running. Return `None` for every
+                /// server to handle every request. Defaults to {queue_group}.
+                fn queue_group(&self) -> Option<&str> {{
+                    {queue_group}
+                }}
+                {stream_idle_timeout}
+                {max_request_size}
+                {function_handlers}
+            }}
 
-    let methods = &service.methods;
-    let reply_methods = filter_methods_by_type(methods, MethodType::RequestResponse);
-    let function_handlers = reply_methods
-        .iter()
-        .map(|method| {
-            let function_name = convert_method_to_function(&method.name);
-            let method_name = &method.proto_name;
-            let input_type = if method.client_streaming {
-                format!("impl ::futures::Stream<Item = {}>", method.input_type)
-            } else {
-                method.input_type.to_string()
-            };
-            let output_type = if method.server_streaming {
-                format!("impl ::futures::Future<Output = ::anyhow::Result<impl ::futures::Stream<Item = {}>>>", method.output_type)
-            } else {
-                format!("impl ::futures::Future<Output = ::anyhow::Result<{}>>", method.output_type)
-            };
-            format!(
-                r#"
-                /// Implementation of {method_name}
-                fn {function_name}(
-                    &self,
-                    _request: {input_type},
-                ) -> {output_type} + Send;
+            /// Share a server with the rest of your application by starting it from an [std::sync::Arc]
+            impl<T: {name}Server + Send + Sync> {name}Server for std::sync::Arc<T> {{
+                fn subject_prefix(&self) -> &str {{
+                    T::subject_prefix(self)
+                }}
+                fn on_error(&self, method: &str, error: {anyhow}::Error) -> {anyhow}::Result<()> {{
+                    T::on_error(self, method, error)
+                }}
+                fn max_concurrent_requests(&self) -> Option<usize> {{
+                    T::max_concurrent_requests(self)
+                }}
+                fn queue_group(&self) -> Option<&str> {{
+                    T::queue_group(self)
+                }}
+                {shared_stream_idle_timeout}
+                {shared_max_request_size}
+                {shared_handlers}
+            }}
+            "#,
+        )
+    }
+
+    /// Create NATS subscriptions for a [Service] trait
+    fn get_server_nats_implementation(&self, service: &Service) -> String {
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+
+        let reply_methods = self.methods_of_type(service, MethodType::RequestResponse);
+        let subscriptions = reply_methods
+            .iter()
+            .map(|method| {
+                // Subscribe to every value of the request fields bound in the subject
+                let subject = self.method_subject_expr(
+                    service,
+                    method,
+                    "subject_prefix",
+                    FieldTokens::Wildcard,
+                );
+                let function_name = convert_method_to_function(&method.name);
+                let method_name = &method.proto_name;
+                format!(
+                    r#"
+                    {runtime}::subscribe(&client, {subject}, queue_group.as_deref())
+                        .await
+                        .context("failed to subscribe for {name} {method_name} messages")?
+                        .map(|message| ("{function_name}", message))
+                        .boxed(),
+                "#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let method_count = reply_methods.len();
+        let start_server = server_entry_point(service);
+        let handle_messages = self.handle_server_messages(service, false);
+        let responses_stream = self.responses_stream(service);
+
+        format!(
+            r#"
+            /// Subscribe to the subjects of every {name} method, returning a future that
+            /// handles requests with the provided [{name}Server] until the subscriptions close.
+            /// Up to [{name}Server::max_concurrent_requests] requests are handled at once.
+            // TODO: Consider this as a trait implementation for types that implement the Server trait
+            #[allow(dead_code)]
+            pub async fn {start_server}<S, T>(
+                server: S,
+                client: T,
+            ) -> {anyhow}::Result<impl {futures}::Future<Output = {anyhow}::Result<()>> + Send>
+            where
+                S: {name}Server + Send + Sync + 'static,
+                T: {runtime}::Transport,
+            {{
+                let subject_prefix = {name}Server::subject_prefix(&server).to_string();
+                let queue_group = {name}Server::queue_group(&server).map(ToString::to_string);
+                {responses_stream}
+                // Subscribe to each method's subject, tagging messages with the method they're for
+                let subscriptions: [{futures}::stream::BoxStream<'static, (&'static str, {async_nats}::Message)>; {method_count}] = [
+                    {subscriptions}
+                ];
+                Ok(async move {{
+                    let messages = {futures}::stream::select_all(subscriptions);
+                    {handle_messages}
+                }})
+            }}
             "#
-            )
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    format!(
-        r#"
-        /// This will be used to implement the handlers for the server
-        pub trait {name}Server {{
-            /// Get the subject prefix for this service. Defaults to
-            /// "nats.proto" and can be overridden with your own implementation.
-            /// If the subject prefix does not include the trailing '.' character, it will be added.
-            fn subject_prefix(&self) -> &'static str {{
-                "nats.proto"
+        )
+    }
+
+    /// Register a [Service] with the NATS services API, adding an endpoint for each of its
+    /// request/reply methods, when the micro service entry point is enabled
+    fn get_server_micro_implementation(&self, service: &Service) -> String {
+        if !self.micro_service {
+            return String::new();
+        }
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let proto_name = &service.proto_name;
+        let service_options = self.service_options(service);
+
+        // The version and description default to the crate's version and the service's comments
+        let version = match service_options.and_then(|options| options.version.as_ref()) {
+            Some(version) => format!("{version:?}"),
+            None => "env!(\"CARGO_PKG_VERSION\")".to_string(),
+        };
+        let description = service_options
+            .and_then(|options| options.description.clone())
+            .unwrap_or_else(|| {
+                service
+                    .comments
+                    .leading
+                    .iter()
+                    .map(|line| line.trim())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            });
+        let description = if description.is_empty() {
+            String::new()
+        } else {
+            format!(".description({description:?})")
+        };
+        let service_metadata = match service_options {
+            Some(options) if !options.metadata.is_empty() => {
+                format!(".metadata({})", metadata_expr(runtime, &options.metadata))
+            }
+            _ => String::new(),
+        };
+
+        let reply_methods = self.methods_of_type(service, MethodType::RequestResponse);
+        let endpoints = reply_methods
+            .iter()
+            .map(|method| {
+                let subject = self.method_subject_expr(
+                    service,
+                    method,
+                    "subject_prefix",
+                    FieldTokens::Wildcard,
+                );
+                let function_name = convert_method_to_function(&method.name);
+                let method_name = &method.proto_name;
+                let endpoint_metadata = match self.method_options(service, method) {
+                    Some(options) if !options.metadata.is_empty() => {
+                        format!(".metadata({})", metadata_expr(runtime, &options.metadata))
+                    }
+                    _ => String::new(),
+                };
+                format!(
+                    r#"
+                    service
+                        .endpoint_builder()
+                        .name("{method_name}")
+                        {endpoint_metadata}
+                        .add({subject})
+                        .await
+                        .map_err(|e| {anyhow}::anyhow!(e))
+                        .context("failed to add {name} {method_name} endpoint")?
+                        .map(|request| ("{function_name}", request))
+                        .boxed(),
+                "#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let method_count = reply_methods.len();
+        let start_micro = micro_entry_point(service);
+        let handle_messages = self.handle_server_messages(service, true);
+        let responses_stream = self.responses_stream(service);
+
+        format!(
+            r#"
+            /// Register {name} as the "{proto_name}" service of the NATS services API, with an
+            /// endpoint for each of its methods, returning a future that handles requests with the
+            /// provided [{name}Server] until the endpoints close. The service can be discovered
+            /// with `$SRV.PING`, `$SRV.INFO` and `$SRV.STATS` requests, and its endpoints always
+            /// join a queue group, `q` unless [{name}Server::queue_group] sets one.
+            #[allow(dead_code)]
+            pub async fn {start_micro}<S>(
+                server: S,
+                client: {async_nats}::Client,
+            ) -> {anyhow}::Result<impl {futures}::Future<Output = {anyhow}::Result<()>> + Send>
+            where
+                S: {name}Server + Send + Sync + 'static,
+            {{
+                let subject_prefix = {name}Server::subject_prefix(&server).to_string();
+                {responses_stream}
+                let mut builder = {async_nats}::service::ServiceExt::service_builder(&client)
+                    {description}
+                    {service_metadata};
+                if let Some(queue_group) = {name}Server::queue_group(&server) {{
+                    builder = builder.queue_group(queue_group);
+                }}
+                let service = builder
+                    .start("{proto_name}", {version})
+                    .await
+                    .map_err(|e| {anyhow}::anyhow!(e))
+                    .context("failed to register {name} with the NATS services API")?;
+                // Add an endpoint for each method, tagging requests with the method they're for
+                let endpoints: [{futures}::stream::BoxStream<'static, (&'static str, {async_nats}::service::Request)>; {method_count}] = [
+                    {endpoints}
+                ];
+                Ok(async move {{
+                    // The service stops responding to discovery requests when it's dropped
+                    let _service = service;
+                    let messages = {futures}::stream::select_all(endpoints);
+                    {handle_messages}
+                }})
             }}
-            {function_handlers}
-        }}
-        "#,
-    )
-}
+            "#
+        )
+    }
 
-/// Create NATS subscriptions for a [Service] trait
-fn get_server_nats_implementation(service: &Service) -> String {
-    let name = &service.name;
+    /// Declare the stream storing the responses of a [Service]'s resumable methods when a server
+    /// starts, if it has any, which their first call creates
+    fn responses_stream(&self, service: &Service) -> String {
+        let RuntimePaths { runtime, .. } = &self.paths;
+        if !service
+            .methods
+            .iter()
+            .any(|method| self.is_resumable(service, method))
+        {
+            return String::new();
+        }
+        let stream = self.stream_name(service);
+        format!("let responses_stream = {runtime}::jetstream::ResponsesStream::new(\"{stream}\");")
+    }
 
-    let methods = &service.methods;
-    let reply_methods = filter_methods_by_type(methods, MethodType::RequestResponse);
-    let matchy = reply_methods
-        .iter()
-        .map(|method| {
-            let function_subject = convert_method_to_subject(&method.name);
-            let function_name = convert_method_to_function(&method.name);
-            let input_type = &method.input_type;
-            if method.server_streaming {
+    /// Serve the work-queue methods of a [Service] from durable JetStream consumers, if it has
+    /// any
+    fn get_server_work_queue_implementation(&self, service: &Service) -> String {
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let proto_name = &service.proto_name;
+
+        let work_queue_methods = self.methods_of_type(service, MethodType::WorkQueue);
+        if work_queue_methods.is_empty() {
+            return String::new();
+        }
+        let stream = self.stream_name(service);
+        let subject_exprs = work_queue_methods
+            .iter()
+            .map(|method| {
+                self.method_subject_expr(service, method, "subject_prefix", FieldTokens::Wildcard)
+            })
+            .collect::<Vec<_>>();
+        let stream_subjects = subject_exprs.join(", ");
+        let consumers = work_queue_methods
+            .iter()
+            .zip(&subject_exprs)
+            .map(|(method, subject)| {
+                let function_name = convert_method_to_function(&method.name);
+                let method_name = &method.proto_name;
                 format!(
                     r#"
-                    Some(".{function_subject}") => {{
-                        let request = {input_type}::decode(message.payload)
-                            .context("failed to decode message payload as {input_type}")?;
-                        let replies = server
-                            .{function_name}(request)
-                            .await
-                            .context("failed to handle {input_type} request")?;
-                        ::futures::pin_mut!(replies);
-                        if let Some(reply_to) = message.reply {{
-                            while let Some(reply) = replies.next().await {{
-                                let mut buf = ::bytes::BytesMut::with_capacity(reply.encoded_len());
-                                reply
-                                    .encode(&mut buf)
-                                    .expect("to encode without reaching capacity");
-                                client
-                                    .publish(reply_to.clone(), buf.into())
-                                    .await
-                                    .context("failed to publish reply")?;
-                            }}
-                        }} else {{
-                            eprintln!("No reply subject found in message");
-                        }}
-                    }},
+                    {runtime}::jetstream::consume(&stream, "{proto_name}_{method_name}", {subject})
+                        .await?
+                        .map(|message| ("{function_name}", message))
+                        .boxed(),
                 "#
                 )
-            } else {
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let matchy = work_queue_methods
+            .iter()
+            .map(|method| {
+                let function_name = convert_method_to_function(&method.name);
+                let input_type = &method.input_type;
+                let output_type = &method.output_type;
+                let check_subject = if field_placeholders(self.method_subject_template(service, method)).is_empty() {
+                    String::new()
+                } else {
+                    let request_fields = FieldTokens::Received {
+                        message: "request",
+                        runtime,
+                    };
+                    let expected = self.method_subject_expr(service, method, "subject_prefix", request_fields);
+                    format!("{runtime}::check_subject(&message.subject, &{expected})?;")
+                };
                 format!(
                     r#"
-                    Some(".{function_subject}") => {{
-                        let request = {input_type}::decode(message.payload)
-                            .context("failed to decode message payload as {input_type}")?;
-                        let reply = server
-                            .{function_name}(request)
-                            .await
-                            .context("failed to handle {input_type} request")?;
+                    "{function_name}" => {{
+                        let ctx = {runtime}::jetstream::context(&message);
+                        let response_headers = ctx.response_headers();
+                        let reply: {anyhow}::Result<{output_type}> = {runtime}::jetstream::in_progress(client, &message, async {{
+                            let request = {runtime}::decode_request::<{input_type}>(message.payload.clone())?;
+                            {check_subject}
+                            server.{function_name}(ctx, request).await
+                        }})
+                        .await;
+                        {runtime}::jetstream::complete(client, &message, reply, response_headers.get()).await
+                    }}
+                "#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let method_count = work_queue_methods.len();
+        let start_work_queue = work_queue_entry_point(service);
+
+        format!(
+            r#"
+            /// Serve the work-queue methods of {name}, whose requests are stored in the "{stream}"
+            /// JetStream stream until they're handled, returning a future that handles requests
+            /// with the provided [{name}Server] until the consumers close. The stream and a
+            /// durable consumer for each method are created if they don't exist, and servers
+            /// sharing them each receive a share of the requests. Requests whose handler fails
+            /// with anything but a 4xx [{runtime}::RemoteError] are delivered again after a delay,
+            /// up to [{runtime}::jetstream::MAX_DELIVERIES] times.
+            #[allow(dead_code)]
+            pub async fn {start_work_queue}<S>(
+                server: S,
+                client: {async_nats}::Client,
+            ) -> {anyhow}::Result<impl {futures}::Future<Output = {anyhow}::Result<()>> + Send>
+            where
+                S: {name}Server + Send + Sync + 'static,
+            {{
+                let subject_prefix = {name}Server::subject_prefix(&server).to_string();
+                let stream = {runtime}::jetstream::work_queue(&client, "{stream}", vec![{stream_subjects}]).await?;
+                // Pull each method's requests, tagging them with the method they're for
+                let consumers: [{futures}::stream::BoxStream<'static, (&'static str, {async_nats}::Message)>; {method_count}] = [
+                    {consumers}
+                ];
+                Ok(async move {{
+                    let messages = {futures}::stream::select_all(consumers);
+                    let limit = {name}Server::max_concurrent_requests(&server);
+                    // The prefix is unused when no method checks its subject against the request
+                    #[allow(unused_variables)]
+                    let (server, client, subject_prefix) = (&server, &client, subject_prefix.as_str());
+                    // Handle up to `limit` requests at once, stopping if the error hook fails
+                    {futures}::TryStreamExt::try_for_each_concurrent(messages.map(Ok), limit, |(method, message)| async move {{
+                        let handled: {anyhow}::Result<()> = match method {{
+                            {matchy}
+                            _ => {{
+                                eprintln!("received message for unknown method {{method}} on subject: {{}}", message.subject);
+                                Ok(())
+                            }}
+                        }};
+                        if let Err(e) = handled {{
+                            {name}Server::on_error(server, method, e)?;
+                        }}
+                        Ok(())
+                    }})
+                    .await
+                }})
+            }}
+            "#
+        )
+    }
+
+    /// Handle the `messages` of a server, a stream of the name of the method each message is
+    /// for along with the message, or the `async_nats::service` request carrying it when `micro`
+    /// is set. Expects `server`, `client` and `subject_prefix` to be in scope, along with
+    /// `responses_stream` for services with resumable methods.
+    fn handle_server_messages(&self, service: &Service, micro: bool) -> String {
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            futures,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        // Replies too large for one message are sent in chunks
+        let publish_reply = if self.chunking {
+            format!("{runtime}::chunks::publish_reply")
+        } else {
+            format!("{runtime}::publish_reply")
+        };
+
+        let reply_methods = self.methods_of_type(service, MethodType::RequestResponse);
+        let matchy = reply_methods
+            .iter()
+            .map(|method| {
+                let function_name = convert_method_to_function(&method.name);
+                let input_type = &method.input_type;
+                // Requests must arrive on the subject their fields map to
+                let check_subject = if field_placeholders(self.method_subject_template(service, method)).is_empty() {
+                    String::new()
+                } else {
+                    let request_fields = FieldTokens::Received {
+                        message: "request",
+                        runtime,
+                    };
+                    let expected = self.method_subject_expr(service, method, "subject_prefix", request_fields);
+                    format!("{runtime}::check_subject(&message.subject, &{expected})?;")
+                };
+                let send_reply = if micro && self.chunking {
+                    format!(
+                        r#"
+                        if let Some(service_request) = service_request.take() {{
+                            // Replies too large for one message can't go through the services API,
+                            // so they're recorded in its stats once they've been sent in chunks
+                            if {runtime}::chunks::fits(client, &reply, &response_headers.get()) {{
+                                {runtime}::micro::respond(client, service_request, reply, response_headers.get()).await?;
+                            }} else if let Some(reply_to) = message.reply {{
+                                {publish_reply}(client, reply_to.to_string(), reply, response_headers.get()).await?;
+                                {runtime}::micro::record(client, service_request, None).await?;
+                            }}
+                        }}
+                    "#
+                    )
+                } else if micro {
+                    format!(
+                        r#"
+                        if let Some(service_request) = service_request.take() {{
+                            {runtime}::micro::respond(client, service_request, reply, response_headers.get()).await?;
+                        }}
+                    "#
+                    )
+                } else {
+                    format!(
+                        r#"
                         if let Some(reply_to) = message.reply {{
-                            let mut buf = ::bytes::BytesMut::with_capacity(reply.encoded_len());
-                            reply
-                                .encode(&mut buf)
-                                .expect("to encode without reaching capacity");
-                            client
-                                .publish(reply_to, buf.into())
-                                .await
-                                .context("failed to publish reply")?;
+                            // Handler errors are sent to the caller as an error reply
+                            {publish_reply}(client, reply_to.to_string(), reply, response_headers.get()).await?;
                         }} else {{
                             eprintln!("No reply subject found in message");
                         }}
-                    }},
+                    "#
+                    )
+                };
+                if self.is_resumable(service, method) {
+                    return format!(
+                        r#"
+                        "{function_name}" => {{
+                            let ctx = {runtime}::RequestContext::new(&message);
+                            let response_headers = ctx.response_headers();
+                            let request = {runtime}::decode_request::<{input_type}>(message.payload.clone())?;
+                            {check_subject}
+                            let replies = server.{function_name}(ctx, request).await;
+                            // Responses are stored in JetStream for the caller to resume
+                            {runtime}::jetstream::serve_resumable(client, &message, responses_stream, replies, response_headers.get()).await?;
+                        }},
+                    "#
+                    );
+                }
+                match (method.client_streaming, method.server_streaming) {
+                    (true, true) => format!(
+                        r#"
+                        "{function_name}" => {{
+                            let ctx = {runtime}::RequestContext::new(&message);
+                            let response_headers = ctx.response_headers();
+                            let (inbox, frames) = {runtime}::accept_session(client, &message)
+                                .await
+                                .context("failed to accept {input_type} stream")?;
+                            let idle_timeout = {name}Server::stream_idle_timeout(server);
+                            let (requests, status) = {runtime}::decode_requests::<{input_type}>(frames, idle_timeout);
+                            // The replies end with an error if the request stream breaks
+                            match server.{function_name}(ctx, requests).await {{
+                                Ok(replies) => {runtime}::publish_responses(client, inbox, replies, &response_headers.get(), &status)
+                                    .await
+                                    .context("failed to publish replies")?,
+                                Err(e) => {runtime}::publish_error(client, inbox, &e, response_headers.get()).await?,
+                            }}
+                        }},
+                    "#
+                    ),
+                    (true, false) => format!(
+                        r#"
+                        "{function_name}" => {{
+                            let ctx = {runtime}::RequestContext::new(&message);
+                            let response_headers = ctx.response_headers();
+                            let (inbox, frames) = {runtime}::accept_session(client, &message)
+                                .await
+                                .context("failed to accept {input_type} stream")?;
+                            let idle_timeout = {name}Server::stream_idle_timeout(server);
+                            let (requests, status) = {runtime}::decode_requests::<{input_type}>(frames, idle_timeout);
+                            let reply = server.{function_name}(ctx, requests).await;
+                            // Never reply with a result computed from a broken request stream
+                            let reply = status.check().and(reply);
+                            {publish_reply}(client, inbox, reply, response_headers.get()).await?;
+                        }},
+                    "#
+                    ),
+                    (false, true) => format!(
+                        r#"
+                        "{function_name}" => {{
+                            let ctx = {runtime}::RequestContext::new(&message);
+                            let response_headers = ctx.response_headers();
+                            let request = {runtime}::decode_request::<{input_type}>(message.payload)?;
+                            {check_subject}
+                            let replies = server.{function_name}(ctx, request).await;
+                            if let Some(reply_to) = message.reply {{
+                                match replies {{
+                                    // Publish each reply followed by an end-of-stream marker
+                                    Ok(replies) => {runtime}::publish_frames(client, reply_to.to_string(), replies, &response_headers.get())
+                                        .await
+                                        .context("failed to publish replies")?,
+                                    Err(e) => {runtime}::publish_error(client, reply_to.to_string(), &e, response_headers.get()).await?,
+                                }}
+                            }} else {{
+                                eprintln!("No reply subject found in message");
+                            }}
+                        }},
+                    "#
+                    ),
+                    (false, false) => format!(
+                        r#"
+                        "{function_name}" => {{
+                            let ctx = {runtime}::RequestContext::new(&message);
+                            let response_headers = ctx.response_headers();
+                            let request = {runtime}::decode_request::<{input_type}>(message.payload)?;
+                            {check_subject}
+                            let reply = server.{function_name}(ctx, request).await;
+                            {send_reply}
+                        }},
+                    "#
+                    ),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let (item, take_message) = if micro {
+            (
+                "service_request",
+                "let message = service_request.message.clone();".to_string(),
+            )
+        } else {
+            ("message", String::new())
+        };
+        // Requests too large for one message arrive in chunks, and are replied to once they've
+        // all been received
+        let take_message = if !self.chunking {
+            take_message
+        } else if micro {
+            format!(
+                r#"
+                let mut service_request = service_request;
+                let max_size = {name}Server::max_request_size(server);
+                let message = match {runtime}::chunks::receive_request(client, service_request.message.clone(), max_size).await {{
+                    Ok(message) => message,
+                    Err(e) => {{
+                        if let Err(e) = {runtime}::micro::respond_error(client, service_request, &e, {async_nats}::HeaderMap::new()).await {{
+                            eprintln!("failed to publish error reply for {{method}}: {{e:#}}");
+                        }}
+                        return {name}Server::on_error(server, method, e);
+                    }}
+                }};
+                service_request.message.reply = message.reply.clone();
+            "#
+            )
+        } else {
+            format!(
+                r#"
+                let reply_to = message.reply.clone();
+                let max_size = {name}Server::max_request_size(server);
+                let message = match {runtime}::chunks::receive_request(client, message, max_size).await {{
+                    Ok(message) => message,
+                    Err(e) => {{
+                        if let Some(reply_to) = reply_to {{
+                            if let Err(e) = {runtime}::publish_error(client, reply_to.to_string(), &e, {async_nats}::HeaderMap::new()).await {{
+                                eprintln!("failed to publish error reply for {{method}}: {{e:#}}");
+                            }}
+                        }}
+                        return {name}Server::on_error(server, method, e);
+                    }}
+                }};
+            "#
+            )
+        };
+        // Each request to an endpoint is replied to once through the services API, which counts
+        // it in the endpoint's stats, and the replies and errors of the handler are sent through
+        // `micro`
+        let (take_message, reply_error, record_streams) = if micro {
+            (
+                format!("{take_message}\nlet mut service_request = Some(service_request);"),
+                format!(
+                    r#"
+                    if let Some(service_request) = service_request.take() {{
+                        if let Err(e) = {runtime}::micro::respond_error(client, service_request, &e, {async_nats}::HeaderMap::new()).await {{
+                            eprintln!("failed to publish error reply for {{method}}: {{e:#}}");
+                        }}
+                    }}
                 "#
-                )
-            }
-        })
-        .collect::<Vec<_>>()
-        .join("\n");
-
-    format!(
-        r#"
-        // TODO: Consider this as a trait implementation for types that implement the Server trait
-        #[allow(dead_code)]
-        pub async fn start_server<S>(
-            server: S,
-            client: async_nats::Client,
-        ) -> ::anyhow::Result<impl ::futures::Future<Output = ::anyhow::Result<()>>>
-        where
-            S: {name}Server + Send + 'static,
-        {{
-            let subject_prefix = server.subject_prefix().trim_end_matches('.');
-            let mut subscription = client
-                .subscribe(format!("{{subject_prefix}}.>"))
-                .await
-                .context("failed to subscribe for {name} messages")?;
-            Ok(async move {{
-                while let Some(message) = subscription.next().await {{
-                    match message.subject.as_str().strip_prefix(&subject_prefix) {{
-                        {matchy}
-                        _ => {{
-                            eprintln!("received message on unknown subject: {{}}", message.subject);
+                ),
+                format!(
+                    r#"
+                    // Streaming calls reply to their own inbox, so they're recorded once they end
+                    if let Some(service_request) = service_request {{
+                        if let Err(e) = {runtime}::micro::record(client, service_request, None).await {{
+                            eprintln!("failed to record {{method}} call: {{e:#}}");
+                        }}
+                    }}
+                "#
+                ),
+            )
+        } else {
+            (
+                format!("{take_message}\nlet reply_to = message.reply.clone();"),
+                format!(
+                    r#"
+                    if let Some(reply_to) = reply_to {{
+                        if let Err(e) = {runtime}::publish_error(client, reply_to.to_string(), &e, {async_nats}::HeaderMap::new()).await {{
+                            eprintln!("failed to publish error reply for {{method}}: {{e:#}}");
                         }}
                     }}
+                "#
+                ),
+                String::new(),
+            )
+        };
+
+        let borrow_responses_stream = if self.responses_stream(service).is_empty() {
+            ""
+        } else {
+            "let responses_stream = &responses_stream;"
+        };
+
+        format!(
+            r#"
+                    let limit = {name}Server::max_concurrent_requests(&server);
+                    // The prefix is unused when no method checks its subject against the request
+                    #[allow(unused_variables)]
+                    let (server, client, subject_prefix) = (&server, &client, subject_prefix.as_str());
+                    {borrow_responses_stream}
+                    // Handle up to `limit` messages at once, stopping if the error hook fails
+                    {futures}::TryStreamExt::try_for_each_concurrent(messages.map(Ok), limit, |(method, {item})| async move {{
+                        {take_message}
+                        let handled: {anyhow}::Result<()> = async {{
+                            match method {{
+                                {matchy}
+                                _ => {{
+                                    eprintln!("received message for unknown method {{method}} on subject: {{}}", message.subject);
+                                }}
+                            }}
+                            Ok(())
+                        }}
+                        .await;
+                        // A message that can't be handled doesn't stop the server unless the
+                        // error hook says so
+                        if let Err(e) = handled {{
+                            {reply_error}
+                            {name}Server::on_error(server, method, e)?;
+                        }}
+                        {record_streams}
+                        Ok(())
+                    }})
+                    .await
+            "#
+        )
+    }
+
+    /// Generate a publisher for the events of a [Service]'s PubSub methods, if it has any
+    fn get_server_publisher(&self, service: &Service) -> String {
+        let RuntimePaths {
+            anyhow,
+            async_nats,
+            runtime,
+            ..
+        } = &self.paths;
+        let name = &service.name;
+        let subject_prefix = &self.subject_prefix;
+
+        let pubsub_methods = self.methods_of_type(service, MethodType::PubSub);
+        if pubsub_methods.is_empty() {
+            return String::new();
+        }
+        let functions = pubsub_methods
+            .iter()
+            .map(|method| {
+                let function_name = publisher_function(&method.name);
+                let method_name = &method.proto_name;
+                let output_type = &method.output_type;
+                let event_fields = FieldTokens::Message {
+                    message: "event",
+                    runtime,
+                };
+                let subject =
+                    self.method_subject_expr(service, method, "self.subject_prefix", event_fields);
+                format!(
+                    r#"
+                    /// Publish a [{output_type}] event to subscribers of {method_name}
+                    pub async fn {function_name}(&self, event: &{output_type}) -> {anyhow}::Result<()> {{
+                        {runtime}::Transport::publish(&self.client, {subject}, None, {async_nats}::HeaderMap::new(), {runtime}::encode(event))
+                            .await
+                            .context("failed to publish {method_name} event")
+                    }}
+                "#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        format!(
+            r#"
+            /// Publishes the events of {name}'s PubSub methods, which clients receive with the
+            /// matching `subscribe_*` methods of [{name}Client]
+            #[allow(dead_code)]
+            #[derive(Clone, Debug)]
+            pub struct {name}Publisher<T = {async_nats}::Client> {{
+                client: T,
+                subject_prefix: ::std::sync::Arc<str>,
+            }}
+
+            #[allow(dead_code)]
+            impl<T: {runtime}::Transport> {name}Publisher<T> {{
+                /// Create a publisher using the default subject prefix, "{subject_prefix}"
+                pub fn new(client: T) -> Self {{
+                    Self {{
+                        client,
+                        subject_prefix: "{subject_prefix}".into(),
+                    }}
+                }}
+
+                /// Publish with a different subject prefix, matching the prefix of your clients
+                pub fn with_subject_prefix(mut self, subject_prefix: impl Into<::std::sync::Arc<str>>) -> Self {{
+                    self.subject_prefix = subject_prefix.into();
+                    self
                 }}
-                Ok(())
-            }})
-        }}
-        "#
-    )
+                {functions}
+            }}
+            "#
+        )
+    }
+}
+
+/// The value generated client functions are implemented for
+struct ClientReceiver<'a> {
+    /// Expression for the `async_nats::Client` that calls are made with
+    connection: &'a str,
+    /// Expression for the default `CallOptions` of every call, if the receiver has them
+    defaults: Option<&'a str>,
 }
 
 #[derive(PartialEq)]
 enum MethodType {
     PubSub,
     RequestResponse,
+    WorkQueue,
+}
+
+/// Name of the generated function that starts the server for a [Service]. Each service
+/// gets its own entry point so that files with multiple services don't define it twice.
+fn server_entry_point(service: &Service) -> String {
+    format!("start_{}_server", service.name.to_case(Case::Snake))
 }
 
-fn get_method_type(method: &prost_build::Method) -> MethodType {
-    /*
-     * This still doesn't work. See https://github.com/tokio-rs/prost/pull/591
-     * when merged, we'll be able to define based on options how to generate the code
-     */
-
-    // let options = &method.options;
-    // let pubsub = options
-    //     .iter()
-    //     .find(|&option| option.identifier_value.clone().unwrap() == "pubsub");
-    // match pubsub {
-    //     Some(_) => MethodType::PubSub,
-    //     None => MethodType::RequestResponse,
-    // }
-
-    // for now, let's get based on the name of the method
-    // if starts with subscribe, it's pubsub; else, it's request/response
-    let name = &method.name;
-    if name.starts_with("subscribe") {
-        MethodType::PubSub
-    } else {
-        MethodType::RequestResponse
+/// Name of the generated function that registers a [Service] with the NATS services API
+fn micro_entry_point(service: &Service) -> String {
+    format!("start_{}_micro", service.name.to_case(Case::Snake))
+}
+
+/// Name of the generated function that serves the work-queue methods of a [Service]
+fn work_queue_entry_point(service: &Service) -> String {
+    format!("start_{}_work_queue", service.name.to_case(Case::Snake))
+}
+
+/// Expression building the metadata of a service or endpoint from its proto options, sorted so
+/// that generated code is stable
+fn metadata_expr(runtime: &str, metadata: &HashMap<String, String>) -> String {
+    let entries = metadata
+        .iter()
+        .collect::<std::collections::BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(key, value)| format!("({key:?}, {value:?})"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!("{runtime}::micro::metadata([{entries}])")
+}
+
+/// Name of the generated publisher function for a PubSub method, e.g. `SubscribePeople`
+/// becomes `publish_people` and `PeopleChanged` becomes `publish_people_changed`
+fn publisher_function(method: &str) -> String {
+    let function_name = convert_method_to_function(method);
+    if function_name == "subscribe" {
+        return "publish".to_string();
     }
+    let event = function_name
+        .strip_prefix("subscribe_")
+        .unwrap_or(&function_name);
+    format!("publish_{event}")
 }
 
 /// Convert a method name to a function name
@@ -404,7 +1982,51 @@ fn convert_method_to_subject(method: &str) -> String {
 
 #[cfg(test)]
 mod test {
-    use crate::{convert_method_to_function, convert_method_to_subject};
+    use std::collections::HashMap;
+
+    use prost_build::{Comments, Method, Service, ServiceGenerator};
+
+    use crate::options::{MethodKind, MethodOptions, ProtoOptions, ServiceOptions};
+
+    use crate::{
+        convert_method_to_function, convert_method_to_subject, NatsServiceGenerator,
+        SubjectStrategy,
+    };
+
+    fn method(name: &str, input_type: &str, output_type: &str) -> Method {
+        Method {
+            name: convert_method_to_function(name),
+            proto_name: name.to_string(),
+            comments: Comments::default(),
+            input_type: input_type.to_string(),
+            output_type: output_type.to_string(),
+            input_proto_type: format!(".simple.{input_type}"),
+            output_proto_type: format!(".simple.{output_type}"),
+            options: prost_types::MethodOptions::default(),
+            client_streaming: false,
+            server_streaming: false,
+        }
+    }
+
+    fn person_service() -> Service {
+        Service {
+            name: "PersonService".to_string(),
+            proto_name: "PersonService".to_string(),
+            package: "simple".to_string(),
+            comments: Comments::default(),
+            methods: vec![method("GetPerson", "GetPersonRequest", "GetPersonResponse")],
+            options: prost_types::ServiceOptions::default(),
+        }
+    }
+
+    fn generate(generator: &mut NatsServiceGenerator, services: Vec<Service>) -> String {
+        let mut buf = String::new();
+        for service in services {
+            generator.generate(service, &mut buf);
+        }
+        generator.finalize_package("simple", &mut buf);
+        buf
+    }
 
     #[test]
     fn can_convert_to_function() {
@@ -428,4 +2050,746 @@ mod test {
         assert_eq!(convert_method_to_subject("StartProvider"), "start.provider");
         assert_eq!(convert_method_to_subject("PutConfig"), "put.config");
     }
+
+    #[test]
+    fn can_convert_with_subject_strategy() {
+        assert_eq!(
+            SubjectStrategy::DotCase.method_subject("GetPerson"),
+            "get.person"
+        );
+        assert_eq!(
+            SubjectStrategy::SnakeCase.method_subject("GetPerson"),
+            "get_person"
+        );
+        assert_eq!(
+            SubjectStrategy::KebabCase.method_subject("GetPerson"),
+            "get-person"
+        );
+    }
+
+    #[test]
+    fn default_generator_emits_client_and_server() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(code.contains("pub trait PersonServiceClient "));
+        assert!(code.contains("pub trait PersonServiceServer "));
+        assert!(code.contains("use ::prost::Message;"));
+        assert!(code.contains("\"nats.proto\""));
+        assert!(code.contains("\"{prefix}.simple.person_service.get.person\""));
+    }
+
+    #[test]
+    fn generates_error_replies() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        // Handler errors are sent to the caller instead of ending the server
+        assert!(code.contains(
+            "nats_rpc::publish_reply(client, reply_to.to_string(), reply, response_headers.get())"
+        ));
+        assert!(code.contains("nats_rpc::decode_reply::<GetPersonResponse>(reply)"));
+        assert!(code.contains("pub struct RemoteError"));
+    }
+
+    #[test]
+    fn server_keeps_handling_messages_after_errors() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(code.contains("nats_rpc::decode_request::<GetPersonRequest>(message.payload)?"));
+        assert!(code.contains("fn on_error(&self, method: &str, error: ::anyhow::Error)"));
+        assert!(code.contains("PersonServiceServer::on_error(server, method, e)?"));
+    }
+
+    #[test]
+    fn server_handles_requests_concurrently() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(code.contains("try_for_each_concurrent(messages.map(Ok), limit,"));
+        assert!(code.contains("S: PersonServiceServer + Send + Sync + 'static,"));
+        assert!(code.contains("PersonServiceServer for std::sync::Arc<T>"));
+        assert!(code.contains("Some(64)"));
+
+        let mut generator = NatsServiceGenerator::builder()
+            .max_concurrent_requests(None)
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(code.contains("Defaults to None."));
+        assert!(!code.contains("Some(64)"));
+    }
+
+    #[test]
+    fn servers_join_queue_group() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(code.contains("Some(\"simple.PersonService\")"));
+        assert!(code.contains("nats_rpc::subscribe(&client,"));
+
+        let service = Service {
+            package: String::new(),
+            ..person_service()
+        };
+        let code = generate(&mut NatsServiceGenerator::default(), vec![service]);
+        assert!(code.contains("Some(\"PersonService\")"));
+    }
+
+    #[test]
+    fn clients_support_runtime_prefix() {
+        let mut generator = NatsServiceGenerator::builder()
+            .auto_subject_prefix(false)
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        // The client struct is the only implementation of the client trait
+        assert_eq!(code.matches("PersonServiceClient for ").count(), 1);
+        assert!(!code.contains("PrefixedClient"));
+        assert!(code.contains("fn subject_prefix(&self) -> &str"));
+        assert!(!code.contains("-> &'static str {"));
+        // Only services with calls streaming their requests can configure how long they wait
+        assert!(!code.contains("fn stream_idle_timeout(&self)"));
+    }
+
+    #[test]
+    fn generates_client_struct() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(code.contains("pub struct PersonServiceNatsClient<T = ::async_nats::Client> {"));
+        assert!(
+            code.contains("pub struct PersonServiceNatsClientBuilder<T = ::async_nats::Client> {")
+        );
+        assert!(code.contains(
+            "impl<T: nats_rpc::Transport> PersonServiceClient for PersonServiceNatsClient<T> {"
+        ));
+        // Calls carry the headers and timeout configured on the client
+        assert!(code.contains("let options = options.with_defaults(&self.options);"));
+    }
+
+    #[test]
+    fn generates_transport_generic_code() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        // Clients send requests through the transport
+        assert!(code.contains("nats_rpc::Transport::request(self.client(),"));
+        // Servers accept any transport
+        assert!(code.contains("pub async fn start_person_service_server<S, T>("));
+        assert!(code.contains("T: nats_rpc::Transport,"));
+    }
+
+    #[test]
+    fn generates_call_options() {
+        let mut generator = NatsServiceGenerator {
+            proto_options: Some(ProtoOptions {
+                methods: HashMap::from([(
+                    "simple.PersonService.GetPerson".to_string(),
+                    MethodOptions {
+                        kind: MethodKind::Unspecified as i32,
+                        subject: None,
+                        timeout_ms: Some(250),
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(code.contains("fn get_person_with_options("));
+        assert!(code.contains("-> ::anyhow::Result<nats_rpc::Reply<GetPersonResponse>>"));
+        assert!(code.contains("PersonServiceClient::get_person_with_options(self, request, nats_rpc::CallOptions::default())"));
+        assert!(code.contains("buf.into(), options)"));
+        // The method's timeout applies before the defaults of the client
+        assert!(code.contains(
+            "let options = options.with_default_timeout(::std::time::Duration::from_millis(250)).with_defaults(&self.options);"
+        ));
+    }
+
+    #[test]
+    fn passes_request_context_to_handlers() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(code.contains("_ctx: nats_rpc::RequestContext,"));
+        assert!(code.contains("T::get_person(self, ctx, request)"));
+        // The context is created from the message, and the headers it collects are replied with
+        assert!(code.contains("let ctx = nats_rpc::RequestContext::new(&message);"));
+        assert!(code.contains("server.get_person(ctx, request)"));
+        assert!(code.contains("reply, response_headers.get())"));
+    }
+
+    #[test]
+    fn generates_pubsub_methods() {
+        let mut service = person_service();
+        service
+            .methods
+            .push(method("SubscribeGreetings", "Person", "Greeting"));
+        let code = generate(&mut NatsServiceGenerator::default(), vec![service]);
+        // Clients subscribe to a stream of events
+        assert!(code.contains("async fn subscribe_greetings("));
+        assert!(code.contains("nats_rpc::decode_events::<Greeting>(sub)"));
+        // Servers publish events rather than handling requests
+        assert!(code.contains("pub struct PersonServicePublisher<T = ::async_nats::Client> {"));
+        assert!(code.contains("pub async fn publish_greetings(&self, event: &Greeting)"));
+        assert!(code.contains("\"{prefix}.simple.person_service.subscribe.greetings\""));
+        assert!(!code.contains("\"subscribe_greetings\" =>"));
+
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(!code.contains("PersonServicePublisher"));
+    }
+
+    #[test]
+    fn reads_proto_options() {
+        let mut service = person_service();
+        service
+            .methods
+            .push(method("PeopleChanged", "Person", "Person"));
+        service
+            .methods
+            .push(method("SubscribeToPerson", "GetPersonRequest", "Person"));
+        let mut generator = NatsServiceGenerator::default();
+        let method_options = |kind: MethodKind, subject: Option<&str>, timeout_ms| MethodOptions {
+            kind: kind as i32,
+            subject: subject.map(ToString::to_string),
+            timeout_ms,
+            ..Default::default()
+        };
+        generator.proto_options = Some(ProtoOptions {
+            services: HashMap::from([(
+                "simple.PersonService".to_string(),
+                ServiceOptions {
+                    queue_group: Some(String::new()),
+                    ..Default::default()
+                },
+            )]),
+            methods: HashMap::from([
+                (
+                    "simple.PersonService.PeopleChanged".to_string(),
+                    method_options(MethodKind::PubSub, None, None),
+                ),
+                (
+                    "simple.PersonService.SubscribeToPerson".to_string(),
+                    method_options(MethodKind::RequestReply, Some("people.{method}"), Some(250)),
+                ),
+            ]),
+        });
+        let code = generate(&mut generator, vec![service]);
+        // Kinds come from options rather than names
+        assert!(code.contains("pub async fn publish_people_changed(&self, event: &Person)"));
+        assert!(code.contains("\"subscribe_to_person\" =>"));
+        // Subjects and timeouts can be set per method
+        assert!(code.contains("\"people.subscribe.to.person\".to_string()"));
+        assert!(code.contains("::std::time::Duration::from_millis(250)"));
+        // An empty queue group disables it
+        assert!(code.contains("Defaults to None."));
+    }
+
+    #[test]
+    fn builder_configures_method_subject() {
+        let mut generator = NatsServiceGenerator::builder()
+            .method_subject(".simple.PersonService.GetPerson", "legacy.people.get")
+            .auto_subject_prefix(false)
+            .build();
+        generator.proto_options = Some(ProtoOptions {
+            methods: HashMap::from([(
+                "simple.PersonService.GetPerson".to_string(),
+                MethodOptions {
+                    kind: MethodKind::Unspecified as i32,
+                    subject: Some("proto.people.get".to_string()),
+                    timeout_ms: None,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+        let code = generate(&mut generator, vec![person_service()]);
+        // Used by the client and the server, in place of the proto option
+        assert_eq!(code.matches("\"legacy.people.get\".to_string()").count(), 2);
+        assert!(!code.contains("proto.people.get"));
+        assert!(!code.contains("person_service.get.person"));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown placeholder")]
+    fn method_subject_rejects_unknown_placeholder() {
+        NatsServiceGenerator::builder().method_subject("simple.PersonService.GetPerson", "{Rpc}");
+    }
+
+    #[test]
+    fn binds_subject_tokens_to_request_fields() {
+        let mut generator = NatsServiceGenerator::builder()
+            .method_subject(
+                "simple.PersonService.GetPerson",
+                "{prefix}.people.{tenant_id}.get",
+            )
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        // Clients fill the token from the request
+        assert!(code.contains("tenant_id = nats_rpc::subject_token(&request.tenant_id)?"));
+        assert!(code.contains("tenant_id = nats_rpc::received_subject_token(&request.tenant_id)?"));
+        // Servers subscribe with a wildcard and check the token against the request
+        assert!(code.contains("\"{prefix}.people.*.get\""));
+        assert!(code.contains("nats_rpc::check_subject(&message.subject,"));
+    }
+
+    #[test]
+    #[should_panic(expected = "isn't supported for client streaming methods")]
+    fn client_streaming_rejects_request_fields() {
+        let mut service = person_service();
+        service.methods[0].client_streaming = true;
+        let mut generator = NatsServiceGenerator::builder()
+            .subject_template("{prefix}.{tenant_id}.{method}")
+            .build();
+        generate(&mut generator, vec![service]);
+    }
+
+    #[test]
+    fn generates_entry_point_per_service() {
+        let order_service = Service {
+            name: "OrderService".to_string(),
+            proto_name: "OrderService".to_string(),
+            methods: vec![method("GetPerson", "GetOrderRequest", "GetOrderResponse")],
+            ..person_service()
+        };
+        let code = generate(
+            &mut NatsServiceGenerator::default(),
+            vec![person_service(), order_service],
+        );
+        assert!(code.contains("pub async fn start_person_service_server<S, T>("));
+        assert!(code.contains("pub async fn start_order_service_server<S, T>("));
+        assert!(!code.contains("pub async fn start_server<S>("));
+        // Prefix lookups are unambiguous when a client implements both prefix traits
+        assert!(code.contains("<Self as OrderServiceClientPrefix>::subject_prefix(self)"));
+        // Methods with the same name on different services get different subjects
+        assert!(code.contains("\"{prefix}.simple.person_service.get.person\""));
+        assert!(code.contains("\"{prefix}.simple.order_service.get.person\""));
+    }
+
+    #[test]
+    fn generates_server_streaming_methods() {
+        let mut service = person_service();
+        service.methods = vec![Method {
+            server_streaming: true,
+            ..method("GetPeople", "GetPersonRequest", "GetPersonResponse")
+        }];
+        let code = generate(&mut NatsServiceGenerator::default(), vec![service]);
+        // Server ends the stream with an end-of-stream marker that the client completes on
+        assert!(code.contains(
+            "nats_rpc::publish_frames(client, reply_to.to_string(), replies, &response_headers.get())"
+        ));
+        assert!(code.contains("nats_rpc::decode_frames::<GetPersonResponse>(sub)"));
+    }
+
+    #[test]
+    fn generates_client_streaming_methods() {
+        let mut service = person_service();
+        service.methods = vec![Method {
+            client_streaming: true,
+            ..method("CountPeople", "Person", "CountPeopleResponse")
+        }];
+        let code = generate(&mut NatsServiceGenerator::default(), vec![service]);
+        assert!(code.contains("pub mod nats_rpc {"));
+        // Client sends a stream of requests over a session opened with the server
+        assert!(code.contains("requests: impl ::futures::Stream<Item = Person> + Send,"));
+        assert!(code.contains("nats_rpc::open_session(self.client(),"));
+        assert!(code.contains("nats_rpc::publish_frames(self.client(), session, requests,"));
+        assert!(code.contains("&inbox, &options)"));
+        // Server feeds the decoded frames into the handler
+        assert!(code.contains("_request: ::futures::stream::BoxStream<'static, Person>,"));
+        assert!(code.contains("nats_rpc::accept_session(client, &message)"));
+        assert!(code.contains("fn stream_idle_timeout(&self) -> ::std::time::Duration {"));
+        assert!(
+            code.contains("let idle_timeout = PersonServiceServer::stream_idle_timeout(server);")
+        );
+        assert!(code.contains("nats_rpc::decode_requests::<Person>(frames, idle_timeout)"));
+        // A broken request stream fails the call instead of being replied to
+        assert!(code.contains("let reply = status.check().and(reply);"));
+    }
+
+    #[test]
+    fn generates_bidirectional_streaming_methods() {
+        let mut service = person_service();
+        service.methods = vec![Method {
+            client_streaming: true,
+            server_streaming: true,
+            ..method("GreetPeople", "Person", "Greeting")
+        }];
+        let code = generate(&mut NatsServiceGenerator::default(), vec![service]);
+        // Client sends requests while receiving responses over the session
+        assert!(code.contains("requests: impl ::futures::Stream<Item = Person> + Send + 'static,"));
+        assert!(
+            code.contains(
+                "nats_rpc::call_bidirectional(::std::clone::Clone::clone(self.client()), session, requests, replies)"
+            )
+        );
+        // Server wires the request stream into the handler and publishes its responses
+        assert!(code.contains("_request: ::futures::stream::BoxStream<'static, Person>,"));
+        assert!(code.contains("::futures::Stream<Item = Greeting> + Send>>"));
+        assert!(code.contains(
+            "nats_rpc::publish_responses(client, inbox, replies, &response_headers.get(), &status)"
+        ));
+    }
+
+    #[test]
+    fn builder_configures_runtime_path() {
+        let mut service = person_service();
+        service.methods[0].client_streaming = true;
+        let mut generator = NatsServiceGenerator::builder()
+            .runtime_path("crate::nats_rpc")
+            .build();
+        let code = generate(&mut generator, vec![service]);
+        assert!(!code.contains("pub mod nats_rpc {"));
+        assert!(code.contains("crate::nats_rpc::open_session(self.client(),"));
+        assert!(generator.runtime_module().contains("pub fn decode_frames"));
+    }
+
+    #[test]
+    fn generates_service_descriptor() {
+        let mut service = person_service();
+        service
+            .methods
+            .push(method("SubscribeGreetings", "Person", "Greeting"));
+        service.methods[0].server_streaming = true;
+        let mut generator = NatsServiceGenerator::builder()
+            .method_subject(
+                "simple.PersonService.GetPerson",
+                "{prefix}.people.{tenant_id}.get",
+            )
+            .build();
+        let code = generate(&mut generator, vec![service]);
+        assert!(code.contains(
+            "pub const PersonServiceDescriptor: nats_rpc::ServiceDescriptor = nats_rpc::ServiceDescriptor {"
+        ));
+        assert!(code.contains("full_name: \"simple.PersonService\","));
+        assert!(code.contains("function_name: \"get_person\","));
+        assert!(code.contains("subject: \"nats.proto.people.*.get\","));
+        assert!(code.contains("subject_template: \"{prefix}.people.{tenant_id}.get\","));
+        assert!(code.contains("input_type: \"simple.GetPersonRequest\","));
+        assert!(code.contains("server_streaming: true,"));
+        // PubSub methods are described too
+        assert!(code.contains("kind: nats_rpc::MethodKind::PubSub,"));
+        assert!(code.contains("subject: \"nats.proto.simple.person_service.subscribe.greetings\","));
+    }
+
+    #[test]
+    fn generates_micro_service() {
+        let mut service = person_service();
+        service.comments.leading = vec![" Look people up".to_string()];
+        let mut generator = NatsServiceGenerator::builder().micro_service(true).build();
+        let code = generate(&mut generator, vec![service.clone()]);
+        assert!(code.contains("pub async fn start_person_service_micro<S>("));
+        assert!(code.contains(".description(\"Look people up\")"));
+        assert!(code.contains(".start(\"PersonService\", env!(\"CARGO_PKG_VERSION\"))"));
+        assert!(code.contains(".name(\"GetPerson\")"));
+        assert!(code.contains(
+            "nats_rpc::micro::respond(client, service_request, reply, response_headers.get())"
+        ));
+        assert!(code.contains(
+            "nats_rpc::micro::respond_error(client, service_request, &e, ::async_nats::HeaderMap::new())"
+        ));
+        assert!(code.contains("nats_rpc::micro::record(client, service_request, None)"));
+        assert!(code.contains("pub mod micro {"));
+
+        generator.proto_options = Some(ProtoOptions {
+            services: HashMap::from([(
+                "simple.PersonService".to_string(),
+                ServiceOptions {
+                    version: Some("1.2.0".to_string()),
+                    description: Some("People directory".to_string()),
+                    metadata: HashMap::from([
+                        ("team".to_string(), "people".to_string()),
+                        ("api".to_string(), "v1".to_string()),
+                    ]),
+                    ..Default::default()
+                },
+            )]),
+            methods: HashMap::from([(
+                "simple.PersonService.GetPerson".to_string(),
+                MethodOptions {
+                    metadata: HashMap::from([("cache".to_string(), "60s".to_string())]),
+                    ..Default::default()
+                },
+            )]),
+        });
+        let code = generate(&mut generator, vec![service]);
+        assert!(code.contains(".start(\"PersonService\", \"1.2.0\")"));
+        assert!(code.contains(".description(\"People directory\")"));
+        assert!(code.contains(
+            ".metadata(nats_rpc::micro::metadata([(\"api\", \"v1\"), (\"team\", \"people\")]))"
+        ));
+        assert!(code.contains(".metadata(nats_rpc::micro::metadata([(\"cache\", \"60s\")]))"));
+
+        // The entry point is only generated when enabled
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(!code.contains("start_person_service_micro"));
+        assert!(!code.contains("pub mod micro {"));
+    }
+
+    fn work_queue_options(service_options: ServiceOptions) -> ProtoOptions {
+        ProtoOptions {
+            services: HashMap::from([("simple.PersonService".to_string(), service_options)]),
+            methods: HashMap::from([(
+                "simple.PersonService.GetPerson".to_string(),
+                MethodOptions {
+                    kind: MethodKind::WorkQueue as i32,
+                    ..Default::default()
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn generates_work_queue_methods() {
+        let mut service = person_service();
+        service.methods.push(method(
+            "FindPerson",
+            "GetPersonRequest",
+            "GetPersonResponse",
+        ));
+        let mut generator = NatsServiceGenerator::builder()
+            .jetstream(true)
+            .serde_json_path("::my_crate::serde_json")
+            .build();
+        generator.proto_options = Some(work_queue_options(ServiceOptions::default()));
+        let code = generate(&mut generator, vec![service]);
+        assert!(code.contains("pub mod jetstream {"));
+        assert!(code.contains("use ::my_crate::serde_json as serde_json;"));
+        // Clients store requests in JetStream, other methods are still called over core NATS
+        assert!(code.contains("let reply = nats_rpc::jetstream::request(self.client(),"));
+        assert!(code.contains("let reply = nats_rpc::Transport::request(self.client(),"));
+        assert!(code.contains("kind: nats_rpc::MethodKind::WorkQueue,"));
+        // Servers pull work-queue requests from a durable consumer instead of subscribing
+        assert!(code.contains("pub async fn start_person_service_work_queue<S>("));
+        assert!(code
+            .contains("nats_rpc::jetstream::work_queue(&client, \"simple_PersonService\", vec!["));
+        assert!(code.contains("nats_rpc::jetstream::consume(&stream, \"PersonService_GetPerson\","));
+        assert!(code.contains("nats_rpc::jetstream::in_progress(client, &message, async {"));
+        assert!(code.contains(
+            "nats_rpc::jetstream::complete(client, &message, reply, response_headers.get())"
+        ));
+        assert!(code.contains("\"find_person\" =>"));
+        assert_eq!(code.matches("\"get_person\" =>").count(), 1);
+
+        generator.proto_options = Some(work_queue_options(ServiceOptions {
+            stream: Some("PEOPLE".to_string()),
+            ..Default::default()
+        }));
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(code.contains("nats_rpc::jetstream::work_queue(&client, \"PEOPLE\", vec!["));
+
+        // The entry point is only generated for services with work-queue methods
+        let service = Service {
+            methods: vec![method(
+                "FindPerson",
+                "GetPersonRequest",
+                "GetPersonResponse",
+            )],
+            ..person_service()
+        };
+        let code = generate(&mut generator, vec![service]);
+        assert!(!code.contains("start_person_service_work_queue"));
+    }
+
+    #[test]
+    #[should_panic(expected = "enable NatsServiceGeneratorBuilder::jetstream")]
+    fn work_queue_requires_jetstream() {
+        let mut generator = NatsServiceGenerator {
+            proto_options: Some(work_queue_options(ServiceOptions::default())),
+            ..Default::default()
+        };
+        generate(&mut generator, vec![person_service()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't stream requests or responses")]
+    fn work_queue_rejects_streaming() {
+        let mut service = person_service();
+        service.methods[0].server_streaming = true;
+        let mut generator = NatsServiceGenerator::builder().jetstream(true).build();
+        generator.proto_options = Some(work_queue_options(ServiceOptions::default()));
+        generate(&mut generator, vec![service]);
+    }
+
+    #[test]
+    #[should_panic(expected = "disable NatsServiceGeneratorBuilder::chunking")]
+    fn work_queue_rejects_chunking() {
+        let mut generator = NatsServiceGenerator::builder()
+            .jetstream(true)
+            .chunking(true)
+            .build();
+        generator.proto_options = Some(work_queue_options(ServiceOptions::default()));
+        generate(&mut generator, vec![person_service()]);
+    }
+
+    #[test]
+    fn generates_resumable_methods() {
+        let mut service = person_service();
+        service.methods = vec![
+            Method {
+                server_streaming: true,
+                ..method("GetPeople", "GetPersonRequest", "GetPersonResponse")
+            },
+            Method {
+                server_streaming: true,
+                ..method("ListPeople", "GetPersonRequest", "GetPersonResponse")
+            },
+        ];
+        let mut generator = NatsServiceGenerator::builder()
+            .jetstream(true)
+            .micro_service(true)
+            .build();
+        generator.proto_options = Some(ProtoOptions {
+            methods: HashMap::from([(
+                "simple.PersonService.GetPeople".to_string(),
+                MethodOptions {
+                    resumable: true,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+        let code = generate(&mut generator, vec![service]);
+        // Clients follow the responses stored in JetStream
+        assert!(code.contains(
+            "nats_rpc::jetstream::call_resumable::<_, GetPersonResponse>(self.client(),"
+        ));
+        assert!(code.contains("\"simple_PersonService\", buf.into(), options)"));
+        // Servers store them, creating the stream on the first call
+        assert!(code.contains(
+            "nats_rpc::jetstream::serve_resumable(client, &message, responses_stream, replies, response_headers.get())"
+        ));
+        assert_eq!(
+            code.matches(
+                "let responses_stream = nats_rpc::jetstream::ResponsesStream::new(\"simple_PersonService\");"
+            )
+            .count(),
+            2
+        );
+        assert_eq!(
+            code.matches("let responses_stream = &responses_stream;")
+                .count(),
+            2
+        );
+        // Other server streaming methods reply over the caller's inbox
+        assert!(code.contains("nats_rpc::decode_frames::<GetPersonResponse>(sub)"));
+    }
+
+    #[test]
+    #[should_panic(expected = "only supported for server streaming methods")]
+    fn resumable_requires_server_streaming() {
+        let mut generator = NatsServiceGenerator::builder().jetstream(true).build();
+        generator.proto_options = Some(ProtoOptions {
+            methods: HashMap::from([(
+                "simple.PersonService.GetPerson".to_string(),
+                MethodOptions {
+                    resumable: true,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+        generate(&mut generator, vec![person_service()]);
+    }
+
+    #[test]
+    fn generates_chunking() {
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert!(!code.contains("chunks::"));
+
+        let mut service = person_service();
+        service.methods.extend([
+            Method {
+                server_streaming: true,
+                ..method("GetPeople", "GetPersonRequest", "GetPersonResponse")
+            },
+            Method {
+                client_streaming: true,
+                ..method("CountPeople", "GetPersonRequest", "GetPersonResponse")
+            },
+        ]);
+        let mut generator = NatsServiceGenerator::builder().chunking(true).build();
+        let code = generate(&mut generator, vec![service]);
+        assert!(code.contains("pub mod chunks {"));
+        // Clients split large requests and join large replies
+        assert!(code.contains("let reply = nats_rpc::chunks::send_request(self.client(),"));
+        assert!(code.contains("nats_rpc::chunks::publish_request(self.client(),"));
+        assert!(code
+            .contains("let reply = nats_rpc::chunks::receive_reply(self.client(), reply).await?;"));
+        // Servers join large requests before handling them, and split large replies
+        assert!(code.contains(
+            "match nats_rpc::chunks::receive_request(client, message, max_size).await {"
+        ));
+        assert!(code.contains("let max_size = PersonServiceServer::max_request_size(server);"));
+        assert!(code.contains(
+            "nats_rpc::chunks::publish_reply(client, reply_to.to_string(), reply, response_headers.get())"
+        ));
+        assert!(code.contains(
+            "nats_rpc::chunks::publish_reply(client, inbox, reply, response_headers.get())"
+        ));
+
+        let mut generator = NatsServiceGenerator::builder()
+            .chunking(true)
+            .micro_service(true)
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        // Replies that fit in one message still go through the services API
+        assert!(
+            code.contains("if nats_rpc::chunks::fits(client, &reply, &response_headers.get()) {")
+        );
+        assert!(code.contains("service_request.message.reply = message.reply.clone();"));
+    }
+
+    #[test]
+    fn builder_configures_loopback_transport() {
+        let runtime = NatsServiceGenerator::default().runtime_module();
+        assert!(!runtime.contains("pub mod loopback {"));
+
+        let generator = NatsServiceGenerator::builder()
+            .loopback_transport(true)
+            .tokio_path("::my_crate::tokio")
+            .build();
+        let runtime = generator.runtime_module();
+        assert!(runtime.contains("pub mod loopback {"));
+        assert!(runtime.contains("use ::my_crate::tokio as tokio;"));
+        assert!(runtime.contains("impl Transport for LoopbackTransport {"));
+    }
+
+    #[test]
+    fn builder_configures_subject_template() {
+        let mut generator = NatsServiceGenerator::builder()
+            .subject_template("{prefix}.{method}")
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(code.contains("\"{prefix}.get.person\""));
+        assert!(!code.contains("person_service.get.person"));
+    }
+
+    #[test]
+    fn auto_subject_prefix_follows_deprecated_feature() {
+        // The feature only sets the default, which the builder overrides
+        let code = generate(&mut NatsServiceGenerator::default(), vec![person_service()]);
+        assert_eq!(
+            code.contains("impl PersonServiceClient for ::async_nats::Client {"),
+            cfg!(feature = "auto_subject_prefix")
+        );
+
+        let mut generator = NatsServiceGenerator::builder()
+            .auto_subject_prefix(false)
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(!code.contains("for ::async_nats::Client"));
+
+        let mut generator = NatsServiceGenerator::builder()
+            .auto_subject_prefix(true)
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(code.contains("impl PersonServiceClientPrefix for ::async_nats::Client {}"));
+        assert!(code.contains("impl PersonServiceClient for ::async_nats::Client {"));
+    }
+
+    #[test]
+    fn builder_configures_generation() {
+        let mut generator = NatsServiceGenerator::builder()
+            .subject_prefix("my.prefix")
+            .subject_strategy(SubjectStrategy::SnakeCase)
+            .generate_server(false)
+            .auto_subject_prefix(true)
+            .emit_imports(false)
+            .async_nats_path("::my_nats")
+            .build();
+        let code = generate(&mut generator, vec![person_service()]);
+        assert!(code.contains("pub trait PersonServiceClient "));
+        assert!(!code.contains("PersonServiceServer"));
+        assert!(!code.contains("use ::prost::Message;"));
+        assert!(code.contains("impl PersonServiceClientPrefix for ::my_nats::Client {}"));
+        assert!(code.contains("\"my.prefix\""));
+        assert!(code.contains(".get_person\""));
+        assert!(code.contains("pub struct PersonServiceNatsClient<T = ::my_nats::Client> {"));
+    }
 }
@@ -0,0 +1,236 @@
+//! Reading the `nats/options.proto` extensions from a raw `FileDescriptorSet`.
+//!
+//! prost decodes descriptors without their extensions, so the messages below mirror only the
+//! parts of `google/protobuf/descriptor.proto` that lead to the options of services and methods,
+//! with the extensions declared as regular fields.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use prost::Message;
+
+/// The `nats/options.proto` file declaring the options read by [NatsServiceGenerator](crate::NatsServiceGenerator)
+///
+/// Write it to `nats/options.proto` in one of your include directories to import it.
+pub const OPTIONS_PROTO: &str = include_str!("../proto/nats/options.proto");
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorSet {
+    #[prost(message, repeated, tag = "1")]
+    file: Vec<FileDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FileDescriptorProto {
+    #[prost(string, optional, tag = "2")]
+    package: Option<String>,
+    #[prost(message, repeated, tag = "6")]
+    service: Vec<ServiceDescriptorProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ServiceDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(message, repeated, tag = "2")]
+    method: Vec<MethodDescriptorProto>,
+    #[prost(message, optional, tag = "3")]
+    options: Option<ServiceOptionsProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MethodDescriptorProto {
+    #[prost(string, optional, tag = "1")]
+    name: Option<String>,
+    #[prost(message, optional, tag = "4")]
+    options: Option<MethodOptionsProto>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ServiceOptionsProto {
+    #[prost(message, optional, tag = "50100")]
+    nats: Option<ServiceOptions>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct MethodOptionsProto {
+    #[prost(message, optional, tag = "50100")]
+    nats: Option<MethodOptions>,
+}
+
+/// The kind of a method, from `nats.MethodKind`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub(crate) enum MethodKind {
+    Unspecified = 0,
+    RequestReply = 1,
+    PubSub = 2,
+    WorkQueue = 3,
+}
+
+/// The `nats.method` options of a method
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct MethodOptions {
+    #[prost(enumeration = "MethodKind", tag = "1")]
+    pub(crate) kind: i32,
+    #[prost(string, optional, tag = "2")]
+    pub(crate) subject: Option<String>,
+    #[prost(uint64, optional, tag = "3")]
+    pub(crate) timeout_ms: Option<u64>,
+    #[prost(map = "string, string", tag = "4")]
+    pub(crate) metadata: HashMap<String, String>,
+    #[prost(bool, tag = "5")]
+    pub(crate) resumable: bool,
+}
+
+/// The `nats.service` options of a service
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct ServiceOptions {
+    #[prost(string, optional, tag = "1")]
+    pub(crate) queue_group: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub(crate) version: Option<String>,
+    #[prost(string, optional, tag = "3")]
+    pub(crate) description: Option<String>,
+    #[prost(map = "string, string", tag = "4")]
+    pub(crate) metadata: HashMap<String, String>,
+    #[prost(string, optional, tag = "5")]
+    pub(crate) stream: Option<String>,
+}
+
+/// Options of the services and methods in a `FileDescriptorSet`, keyed by their fully
+/// qualified names, e.g. `example.PersonService` and `example.PersonService.GetPerson`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProtoOptions {
+    pub(crate) services: HashMap<String, ServiceOptions>,
+    pub(crate) methods: HashMap<String, MethodOptions>,
+}
+
+impl ProtoOptions {
+    /// Read the options from the encoded `FileDescriptorSet` at `path`
+    ///
+    /// # Panics
+    /// Panics if the file can't be read or isn't a `FileDescriptorSet`, as generation can't
+    /// continue without the options it declares.
+    pub(crate) fn load(path: &Path) -> Self {
+        let bytes = std::fs::read(path).unwrap_or_else(|e| {
+            panic!(
+                "failed to read file descriptor set {}: {e}, make sure prost_build::Config::file_descriptor_set_path is set to the same path",
+                path.display()
+            )
+        });
+        Self::decode(&bytes).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode file descriptor set {}: {e}",
+                path.display()
+            )
+        })
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, prost::DecodeError> {
+        let set = FileDescriptorSet::decode(bytes)?;
+        let mut options = ProtoOptions::default();
+        for file in set.file {
+            let package = file.package.unwrap_or_default();
+            for service in file.service {
+                let service_name = qualified_name(&package, &service.name.unwrap_or_default());
+                if let Some(nats) = service.options.and_then(|options| options.nats) {
+                    options.services.insert(service_name.clone(), nats);
+                }
+                for method in service.method {
+                    let method_name = format!("{service_name}.{}", method.name.unwrap_or_default());
+                    if let Some(nats) = method.options.and_then(|options| options.nats) {
+                        options.methods.insert(method_name, nats);
+                    }
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// Get the options of a method of a service
+    pub(crate) fn method(&self, service: &str, method: &str) -> Option<&MethodOptions> {
+        self.methods.get(&format!("{service}.{method}"))
+    }
+}
+
+/// Get the fully qualified name of a service in a package
+pub(crate) fn qualified_name(package: &str, name: &str) -> String {
+    if package.is_empty() {
+        name.to_string()
+    } else {
+        format!("{package}.{name}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use prost::Message;
+
+    use super::{
+        FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto, MethodKind, MethodOptions,
+        MethodOptionsProto, ProtoOptions, ServiceDescriptorProto, ServiceOptions,
+        ServiceOptionsProto,
+    };
+
+    #[test]
+    fn can_read_extensions() {
+        let set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("example".to_string()),
+                service: vec![ServiceDescriptorProto {
+                    name: Some("PersonService".to_string()),
+                    method: vec![
+                        MethodDescriptorProto {
+                            name: Some("PeopleChanged".to_string()),
+                            options: Some(MethodOptionsProto {
+                                nats: Some(MethodOptions {
+                                    kind: MethodKind::PubSub as i32,
+                                    subject: None,
+                                    timeout_ms: Some(500),
+                                    metadata: HashMap::from([(
+                                        "owner".to_string(),
+                                        "people-team".to_string(),
+                                    )]),
+                                    resumable: true,
+                                }),
+                            }),
+                        },
+                        MethodDescriptorProto {
+                            name: Some("GetPerson".to_string()),
+                            options: None,
+                        },
+                    ],
+                    options: Some(ServiceOptionsProto {
+                        nats: Some(ServiceOptions {
+                            queue_group: Some(String::new()),
+                            version: Some("1.2.0".to_string()),
+                            ..Default::default()
+                        }),
+                    }),
+                }],
+            }],
+        };
+        let options = ProtoOptions::decode(&set.encode_to_vec()).expect("to decode");
+        let method = options
+            .method("example.PersonService", "PeopleChanged")
+            .expect("method options");
+        assert_eq!(method.kind(), MethodKind::PubSub);
+        assert_eq!(method.timeout_ms, Some(500));
+        assert_eq!(method.metadata["owner"], "people-team");
+        assert!(method.resumable);
+        assert!(options
+            .method("example.PersonService", "GetPerson")
+            .is_none());
+        assert_eq!(
+            options.services["example.PersonService"].queue_group,
+            Some(String::new())
+        );
+        assert_eq!(
+            options.services["example.PersonService"].version.as_deref(),
+            Some("1.2.0")
+        );
+    }
+}
@@ -0,0 +1,268 @@
+use convert_case::{Case, Casing};
+
+use crate::convert_method_to_subject;
+
+/// The default subject template used by generated clients and servers.
+///
+/// Including the package and service keeps services that share a NATS account from
+/// receiving each other's requests.
+pub const DEFAULT_SUBJECT_TEMPLATE: &str = "{prefix}.{package}.{service}.{method}";
+
+/// Placeholders supported in a subject template
+const PLACEHOLDERS: &[&str] = &["{prefix}", "{package}", "{service}", "{method}"];
+
+/// Strategy used to turn an RPC method name into NATS subject tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SubjectStrategy {
+    /// `GetPerson` becomes `get.person`
+    #[default]
+    DotCase,
+    /// `GetPerson` becomes `get_person`
+    SnakeCase,
+    /// `GetPerson` becomes `get-person`
+    KebabCase,
+}
+
+impl SubjectStrategy {
+    /// Convert a method name to subject tokens using this strategy
+    pub fn method_subject(&self, method: &str) -> String {
+        match self {
+            SubjectStrategy::DotCase => convert_method_to_subject(method),
+            SubjectStrategy::SnakeCase => method.to_case(Case::Snake),
+            SubjectStrategy::KebabCase => method.to_case(Case::Kebab),
+        }
+    }
+}
+
+/// Validate a subject template, panicking with a helpful message if it is invalid.
+///
+/// Templates must reference `{method}` so that every RPC of a service gets its own subject,
+/// and may only use the placeholders `{prefix}`, `{package}`, `{service}` and `{method}`.
+pub(crate) fn validate_subject_template(template: &str) {
+    assert!(
+        template.contains("{method}"),
+        "subject template `{template}` must contain the {{method}} placeholder"
+    );
+    validate_placeholders(template);
+}
+
+/// Validate that a subject template, or a subject override for a single method, only uses the
+/// supported placeholders, panicking with a helpful message if it doesn't. Placeholders other
+/// than [PLACEHOLDERS] are bound to fields of the request, and must be snake_case field names.
+pub(crate) fn validate_placeholders(template: &str) {
+    let mut remaining = template.to_string();
+    for placeholder in PLACEHOLDERS {
+        remaining = remaining.replace(placeholder, "");
+    }
+    for field in field_placeholders(template) {
+        remaining = remaining.replacen(&format!("{{{field}}}"), "", 1);
+    }
+    assert!(
+        !remaining.contains('{') && !remaining.contains('}'),
+        "subject template `{template}` contains an unknown placeholder, supported placeholders are {} and snake_case request field names",
+        PLACEHOLDERS.join(", ")
+    );
+}
+
+/// Get the placeholders of a subject template that are bound to request fields, in order
+pub(crate) fn field_placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .filter(|name| !PLACEHOLDERS.contains(&format!("{{{name}}}").as_str()))
+        .filter(|name| {
+            name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        })
+        .collect()
+}
+
+/// How placeholders bound to request fields are rendered by [subject_expr]
+pub(crate) enum FieldTokens<'a> {
+    /// Match any value with a `*` wildcard, for subscriptions
+    Wildcard,
+    /// Read each field from the message in the variable `message`, converting it to a subject
+    /// token with `subject_token` from the runtime module at `runtime`
+    Message { message: &'a str, runtime: &'a str },
+    /// Like `Message`, for a request received by a server, converting each field with
+    /// `received_subject_token` so that invalid fields are rejected as the caller's error
+    Received { message: &'a str, runtime: &'a str },
+}
+
+/// Resolve everything in a subject template except `{prefix}` and request fields, which are
+/// only known at runtime. Empty tokens, such as the `{package}` of a proto file without a
+/// package, are dropped from the subject.
+pub(crate) fn resolve_subject_template(
+    template: &str,
+    package: &str,
+    service: &str,
+    method: &str,
+) -> String {
+    template
+        .replace("{package}", package)
+        .replace("{service}", &service.to_case(Case::Snake))
+        .replace("{method}", method)
+        .split('.')
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Render a subject template into a Rust expression evaluating to the subject `String`.
+///
+/// The template is resolved with [resolve_subject_template]. The prefix is only known at
+/// runtime, so `prefix_expr` is evaluated by the generated code, and request fields are
+/// rendered according to `fields`.
+pub(crate) fn subject_expr(
+    template: &str,
+    prefix_expr: &str,
+    fields: FieldTokens,
+    package: &str,
+    service: &str,
+    method: &str,
+) -> String {
+    let mut subject = resolve_subject_template(template, package, service, method);
+    let mut args = Vec::new();
+    if subject.contains("{prefix}") {
+        args.push(format!("prefix = {prefix_expr}.trim_end_matches('.')"));
+    }
+    for field in field_placeholders(template) {
+        match fields {
+            FieldTokens::Wildcard => subject = subject.replace(&format!("{{{field}}}"), "*"),
+            FieldTokens::Message { message, runtime } => args.push(format!(
+                "{field} = {runtime}::subject_token(&{message}.{field})?"
+            )),
+            FieldTokens::Received { message, runtime } => args.push(format!(
+                "{field} = {runtime}::received_subject_token(&{message}.{field})?"
+            )),
+        }
+    }
+    if args.is_empty() {
+        format!(r#""{subject}".to_string()"#)
+    } else {
+        format!(r#"format!("{subject}", {})"#, args.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        field_placeholders, resolve_subject_template, subject_expr, validate_subject_template,
+        FieldTokens, DEFAULT_SUBJECT_TEMPLATE,
+    };
+
+    #[test]
+    fn can_render_default_template() {
+        assert_eq!(
+            subject_expr(
+                DEFAULT_SUBJECT_TEMPLATE,
+                "prefix",
+                FieldTokens::Wildcard,
+                "simple",
+                "PersonService",
+                "get.person"
+            ),
+            r#"format!("{prefix}.simple.person_service.get.person", prefix = prefix.trim_end_matches('.'))"#
+        );
+    }
+
+    #[test]
+    fn can_render_without_package_or_prefix() {
+        assert_eq!(
+            subject_expr(
+                DEFAULT_SUBJECT_TEMPLATE,
+                "prefix",
+                FieldTokens::Wildcard,
+                "",
+                "PersonService",
+                "get.person"
+            ),
+            r#"format!("{prefix}.person_service.get.person", prefix = prefix.trim_end_matches('.'))"#
+        );
+        assert_eq!(
+            subject_expr(
+                "people.{method}",
+                "prefix",
+                FieldTokens::Wildcard,
+                "simple",
+                "PersonService",
+                "get.person"
+            ),
+            r#""people.get.person".to_string()"#
+        );
+    }
+
+    #[test]
+    fn can_render_request_fields() {
+        let template = "{prefix}.orders.{tenant_id}.{region}.{method}";
+        assert_eq!(field_placeholders(template), ["tenant_id", "region"]);
+        validate_subject_template(template);
+        assert_eq!(
+            subject_expr(
+                template,
+                "prefix",
+                FieldTokens::Message {
+                    message: "request",
+                    runtime: "nats_rpc"
+                },
+                "simple",
+                "OrderService",
+                "get"
+            ),
+            r#"format!("{prefix}.orders.{tenant_id}.{region}.get", prefix = prefix.trim_end_matches('.'), tenant_id = nats_rpc::subject_token(&request.tenant_id)?, region = nats_rpc::subject_token(&request.region)?)"#
+        );
+        assert_eq!(
+            subject_expr(
+                template,
+                "prefix",
+                FieldTokens::Received {
+                    message: "request",
+                    runtime: "nats_rpc"
+                },
+                "simple",
+                "OrderService",
+                "get"
+            ),
+            r#"format!("{prefix}.orders.{tenant_id}.{region}.get", prefix = prefix.trim_end_matches('.'), tenant_id = nats_rpc::received_subject_token(&request.tenant_id)?, region = nats_rpc::received_subject_token(&request.region)?)"#
+        );
+        assert_eq!(
+            subject_expr(
+                template,
+                "prefix",
+                FieldTokens::Wildcard,
+                "simple",
+                "OrderService",
+                "get"
+            ),
+            r#"format!("{prefix}.orders.*.*.get", prefix = prefix.trim_end_matches('.'))"#
+        );
+    }
+
+    #[test]
+    fn can_resolve_template() {
+        assert_eq!(
+            resolve_subject_template(
+                "{prefix}.{package}.orders.{tenant_id}.{method}",
+                "",
+                "OrderService",
+                "get"
+            ),
+            "{prefix}.orders.{tenant_id}.get"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "must contain the {method} placeholder")]
+    fn template_requires_method() {
+        validate_subject_template("{prefix}.{service}");
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown placeholder")]
+    fn template_rejects_unknown_placeholder() {
+        validate_subject_template("{prefix}.{Rpc}.{method}");
+    }
+}
@@ -0,0 +1,52 @@
+use protobuf_nats_service_generator::{NatsServiceGenerator, SubjectStrategy};
+
+fn main() -> std::io::Result<()> {
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").expect("OUT_DIR is set"));
+    let includes = ["proto/", "../../proto/"];
+
+    // Turn on every builder option, so that the tests cover all of the code that can be
+    // generated for a service
+    let people_descriptors = out_dir.join("people.fds");
+    prost_build::Config::new()
+        .file_descriptor_set_path(&people_descriptors)
+        .service_generator(Box::new(
+            NatsServiceGenerator::builder()
+                .subject_prefix("test.rpc")
+                .auto_subject_prefix(true)
+                .generate_client(true)
+                .generate_server(true)
+                .max_concurrent_requests(Some(8))
+                .subject_strategy(SubjectStrategy::SnakeCase)
+                .subject_template("{prefix}.{package}.{method}")
+                .method_subject("people.PeopleService.GetPerson", "{prefix}.{tenant_id}.get")
+                .emit_imports(true)
+                .anyhow_path("crate::deps::anyhow")
+                .async_nats_path("crate::deps::async_nats")
+                .bytes_path("crate::deps::bytes")
+                .futures_path("crate::deps::futures")
+                .prost_path("crate::deps::prost")
+                .serde_json_path("crate::deps::serde_json")
+                .tokio_path("crate::deps::tokio")
+                .loopback_transport(true)
+                .micro_service(true)
+                .jetstream(true)
+                .chunking(true)
+                .file_descriptor_set_path(&people_descriptors)
+                .build(),
+        ))
+        .compile_protos(&["proto/people.proto"], &includes)?;
+
+    // Work-queue and resumable methods, using the runtime module generated for `people`
+    let orders_descriptors = out_dir.join("orders.fds");
+    prost_build::Config::new()
+        .file_descriptor_set_path(&orders_descriptors)
+        .service_generator(Box::new(
+            NatsServiceGenerator::builder()
+                .jetstream(true)
+                .file_descriptor_set_path(&orders_descriptors)
+                .runtime_path("crate::people::nats_rpc")
+                .build(),
+        ))
+        .compile_protos(&["proto/orders.proto"], &includes)?;
+    Ok(())
+}
@@ -0,0 +1,15 @@
+//! Code generated with every option of `NatsServiceGenerator::builder()` turned on, see
+//! `build.rs`. The tests in `tests/` run it over the loopback transport, without a NATS server.
+
+/// Dependencies of the generated code, reached through the paths set in `build.rs`
+pub mod deps {
+    pub use ::{anyhow, async_nats, bytes, futures, prost, serde_json, tokio};
+}
+
+pub mod people {
+    include!(concat!(env!("OUT_DIR"), "/people.rs"));
+}
+
+pub mod orders {
+    include!(concat!(env!("OUT_DIR"), "/orders.rs"));
+}
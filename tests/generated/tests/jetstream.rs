@@ -0,0 +1,280 @@
+use futures::{stream, FutureExt, Stream, StreamExt};
+use generated_tests::orders::*;
+use generated_tests::people::nats_rpc;
+use nats_rpc::jetstream::{complete, in_progress, MAX_DELIVERIES, PROGRESS_INTERVAL};
+use nats_rpc::loopback::LoopbackTransport;
+use nats_rpc::{RemoteError, Transport};
+
+struct Orders;
+
+impl OrderServiceServer for Orders {
+    async fn place_order(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: Order,
+    ) -> anyhow::Result<Order> {
+        Ok(request)
+    }
+
+    async fn list_items(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: Order,
+    ) -> anyhow::Result<impl Stream<Item = Order> + Send> {
+        Ok(stream::iter((1..=request.items).map(move |item| Order {
+            id: request.id,
+            items: item,
+        })))
+    }
+}
+
+async fn start(transport: &LoopbackTransport) -> tokio::task::JoinHandle<()> {
+    let server = start_order_service_server(Orders, transport.clone())
+        .await
+        .expect("should be able to start server");
+    tokio::spawn(async move {
+        server.await.expect("server should keep running");
+    })
+}
+
+const CALLER_INBOX: &str = "caller.inbox";
+
+/// A work-queue request as a JetStream consumer delivers it for the `delivered`th time, with
+/// the subject its acknowledgements are published to as its reply subject
+fn work_queue_request(delivered: u64) -> async_nats::Message {
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(nats_rpc::INBOX_HEADER, CALLER_INBOX);
+    async_nats::Message {
+        subject: "nats.proto.orders.order_service.place.order".into(),
+        reply: Some(
+            format!("$JS.ACK.TEST_ORDERS.OrderService_PlaceOrder.{delivered}.7.7.0.0").into(),
+        ),
+        payload: bytes::Bytes::new(),
+        headers: Some(headers),
+        status: None,
+        description: None,
+        length: 0,
+    }
+}
+
+/// Complete the `delivered`th delivery of a work-queue request with `reply`, returning the
+/// result along with the acknowledgement JetStream received
+async fn complete_delivery(
+    transport: &LoopbackTransport,
+    delivered: u64,
+    reply: anyhow::Result<Order>,
+) -> (anyhow::Result<()>, String) {
+    let request = work_queue_request(delivered);
+    let ack_subject = request.reply.as_ref().unwrap().to_string();
+    let mut acks = transport.subscribe(ack_subject, None).await.unwrap();
+    let completed = complete(transport, &request, reply, async_nats::HeaderMap::new()).await;
+    let ack = acks.next().await.expect("request should be acknowledged");
+    (completed, String::from_utf8(ack.payload.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn work_queue_requests_are_delivered_until_they_succeed_or_run_out() {
+    let transport = LoopbackTransport::new();
+    let mut caller = transport
+        .subscribe(CALLER_INBOX.to_string(), None)
+        .await
+        .unwrap();
+    let unavailable = || Err(RemoteError::new(503, "try again").into());
+
+    // Failed deliveries are delivered again after a delay doubling each time, without replying
+    let (completed, ack) = complete_delivery(&transport, 1, unavailable()).await;
+    assert!(completed.is_err());
+    assert_eq!(ack, r#"-NAK {"delay":1000000000}"#);
+    let (completed, ack) = complete_delivery(&transport, 3, unavailable()).await;
+    assert!(completed.is_err());
+    assert_eq!(ack, r#"-NAK {"delay":4000000000}"#);
+    assert!(caller.next().now_or_never().is_none());
+
+    // The last delivery replies with its error and removes the request
+    let (completed, ack) =
+        complete_delivery(&transport, MAX_DELIVERIES as u64, unavailable()).await;
+    assert!(completed.is_err());
+    assert_eq!(ack, "+TERM");
+    let reply = caller.next().await.unwrap();
+    assert_eq!(RemoteError::from_message(&reply).unwrap().code, 503);
+
+    // Client errors would fail again, so they're replied to and removed at once
+    let not_found = Err(RemoteError::new(404, "no order").into());
+    let (completed, ack) = complete_delivery(&transport, 1, not_found).await;
+    assert!(completed.is_ok());
+    assert_eq!(ack, "+TERM");
+    let reply = caller.next().await.unwrap();
+    assert_eq!(RemoteError::from_message(&reply).unwrap().code, 404);
+
+    let order = Order { id: 1, items: 2 };
+    let (completed, ack) = complete_delivery(&transport, 2, Ok(order)).await;
+    assert!(completed.is_ok());
+    assert_eq!(ack, "+ACK");
+    let reply = caller.next().await.unwrap();
+    assert_eq!(
+        nats_rpc::decode_reply::<Order>(reply).unwrap().message,
+        order
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn long_running_work_queue_requests_stay_in_progress() {
+    let transport = LoopbackTransport::new();
+    let request = work_queue_request(1);
+    let ack_subject = request.reply.as_ref().unwrap().to_string();
+    let mut acks = transport.subscribe(ack_subject, None).await.unwrap();
+
+    let handled = in_progress(&transport, &request, async {
+        tokio::time::sleep(2 * PROGRESS_INTERVAL + PROGRESS_INTERVAL / 2).await;
+        "handled"
+    })
+    .await;
+    assert_eq!(handled, "handled");
+
+    // JetStream is told the request is in progress every interval while its handler runs
+    for _ in 0..2 {
+        let ack = acks.next().now_or_never().flatten().unwrap();
+        assert_eq!(&ack.payload[..], b"+WPI");
+    }
+    assert!(acks.next().now_or_never().is_none());
+}
+
+/// Just enough of the JetStream API for resumable calls: a stream storing every message
+/// published under `nats_rpc.responses`, and push consumers delivering the messages stored on
+/// their filter subject. The first consumer skips the message with the stream sequence
+/// `skipped`, as if it was lost. Returns the requests creating consumers.
+fn fake_jetstream(
+    transport: &LoopbackTransport,
+    skipped: u64,
+) -> std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>> {
+    struct Consumer {
+        name: String,
+        deliver_subject: String,
+        filter_subject: String,
+        next: u64,
+        skipped: Option<u64>,
+    }
+
+    let created = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let transport = transport.clone();
+    let requests = created.clone();
+    let (ready, subscribed) = std::sync::mpsc::channel();
+    tokio::spawn(async move {
+        let api = transport.subscribe("$JS.API.>".to_string(), None).await;
+        let published = transport
+            .subscribe("nats_rpc.responses.>".to_string(), None)
+            .await;
+        ready.send(()).unwrap();
+        let mut messages = stream::select(
+            api.unwrap().map(|message| (true, message)),
+            published.unwrap().map(|message| (false, message)),
+        );
+        let mut stored = Vec::<async_nats::Message>::new();
+        let mut consumers = Vec::<Consumer>::new();
+        while let Some((is_api, message)) = messages.next().await {
+            let subject = message.subject.to_string();
+            let response = if !is_api {
+                stored.push(message.clone());
+                serde_json::json!({ "stream": "TEST_ORDERS_RESPONSES", "seq": stored.len() })
+            } else if subject.starts_with("$JS.API.CONSUMER.CREATE.") {
+                let request: serde_json::Value = serde_json::from_slice(&message.payload).unwrap();
+                let config = &request["config"];
+                let mut created = requests.lock().unwrap();
+                consumers.push(Consumer {
+                    name: config["name"].as_str().unwrap().to_string(),
+                    deliver_subject: config["deliver_subject"].as_str().unwrap().to_string(),
+                    filter_subject: config["filter_subject"].as_str().unwrap().to_string(),
+                    next: config["opt_start_seq"].as_u64().unwrap_or(1),
+                    skipped: created.is_empty().then_some(skipped),
+                });
+                created.push(request);
+                serde_json::json!({ "type": "io.nats.jetstream.api.v1.consumer_create_response" })
+            } else if subject.starts_with("$JS.API.CONSUMER.DELETE.") {
+                consumers.retain(|consumer| !subject.ends_with(&consumer.name));
+                serde_json::json!({ "success": true })
+            } else {
+                // Creating and purging the stream
+                serde_json::json!({ "success": true })
+            };
+            if let Some(reply) = message.reply {
+                let response = response.to_string().into();
+                let headers = async_nats::HeaderMap::new();
+                transport
+                    .publish(reply.to_string(), None, headers, response)
+                    .await
+                    .unwrap();
+            }
+
+            // Deliver the messages stored since each consumer last delivered
+            for consumer in &mut consumers {
+                while consumer.next <= stored.len() as u64 {
+                    let sequence = consumer.next;
+                    consumer.next += 1;
+                    let message = &stored[sequence as usize - 1];
+                    if message.subject.as_str() != consumer.filter_subject
+                        || consumer.skipped == Some(sequence)
+                    {
+                        continue;
+                    }
+                    let ack = format!(
+                        "$JS.ACK.TEST_ORDERS_RESPONSES.{}.1.{sequence}.{sequence}.0.0",
+                        consumer.name
+                    );
+                    transport
+                        .publish(
+                            consumer.deliver_subject.clone(),
+                            Some(ack),
+                            message.headers.clone().unwrap_or_default(),
+                            message.payload.clone(),
+                        )
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+    });
+    subscribed.recv().unwrap();
+    created
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resumable_calls_resume_after_the_last_response_received() {
+    let transport = LoopbackTransport::new();
+    let created = fake_jetstream(&transport, 2);
+    let server = start(&transport).await;
+    let client = OrderServiceNatsClient::new(transport);
+
+    let items = client
+        .list_items(Order { id: 1, items: 3 })
+        .await
+        .expect("should be able to call resumable method")
+        .map(|item| item.expect("should be able to receive item").items)
+        .collect::<Vec<_>>()
+        .await;
+    // The second response was lost, and delivered again by a consumer replacing the first
+    assert_eq!(items, [1, 2, 3]);
+    let created = created.lock().unwrap();
+    assert_eq!(created.len(), 2);
+    assert_eq!(created[0]["config"]["deliver_policy"], "all");
+    assert_eq!(created[1]["config"]["deliver_policy"], "by_start_sequence");
+    assert_eq!(created[1]["config"]["opt_start_seq"], 2);
+
+    server.abort();
+}
+
+// Servers with resumable methods create their responses stream on the first call, so they
+// start on transports without JetStream
+#[tokio::test]
+async fn resumable_servers_start_without_jetstream() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport).await;
+
+    let client = OrderServiceNatsClient::new(transport);
+    let call = client.list_items(Order { id: 1, items: 2 });
+    let reply = tokio::time::timeout(std::time::Duration::from_secs(5), call)
+        .await
+        .expect("resumable call should fail without JetStream");
+    assert!(reply.is_err());
+
+    server.abort();
+}
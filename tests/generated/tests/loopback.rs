@@ -0,0 +1,541 @@
+use futures::{stream, stream::BoxStream, Stream, StreamExt};
+use generated_tests::people::*;
+use nats_rpc::loopback::LoopbackTransport;
+use nats_rpc::{CallOptions, RemoteError, Transport};
+
+struct People {
+    prefix: String,
+    idle_timeout: std::time::Duration,
+    limit: Option<usize>,
+    max_request_size: usize,
+}
+
+impl Default for People {
+    fn default() -> Self {
+        Self {
+            prefix: "test.rpc".to_string(),
+            idle_timeout: nats_rpc::STREAM_IDLE_TIMEOUT,
+            limit: Some(8),
+            max_request_size: nats_rpc::chunks::MAX_CHUNKED_PAYLOAD,
+        }
+    }
+}
+
+fn person(tenant_id: &str, id: u32, photo_size: u32) -> Person {
+    Person {
+        tenant_id: tenant_id.to_string(),
+        id,
+        name: format!("Person {id}"),
+        photo: (0..photo_size).map(|i| (i % 251) as u8).collect(),
+    }
+}
+
+impl PeopleServiceServer for People {
+    fn subject_prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn stream_idle_timeout(&self) -> std::time::Duration {
+        self.idle_timeout
+    }
+
+    fn max_concurrent_requests(&self) -> Option<usize> {
+        self.limit
+    }
+
+    fn max_request_size(&self) -> usize {
+        self.max_request_size
+    }
+
+    async fn get_person(
+        &self,
+        ctx: nats_rpc::RequestContext,
+        request: PersonRequest,
+    ) -> anyhow::Result<Person> {
+        if let Some(trace) = ctx.header("X-Trace") {
+            ctx.set_response_header("X-Trace", trace);
+        }
+        if request.id == 0 {
+            return Err(RemoteError::new(404, "person not found").into());
+        }
+        Ok(person(&request.tenant_id, request.id, request.photo_size))
+    }
+
+    async fn list_people(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: PersonRequest,
+    ) -> anyhow::Result<impl Stream<Item = Person> + Send> {
+        if request.id == 0 {
+            return Err(RemoteError::new(404, "no people to list").into());
+        }
+        Ok(stream::iter((1..=request.id).map(move |id| {
+            person(&request.tenant_id, id, request.photo_size)
+        })))
+    }
+
+    async fn count_people(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: BoxStream<'static, Person>,
+    ) -> anyhow::Result<Count> {
+        let people = request.collect::<Vec<_>>().await;
+        Ok(Count {
+            people: people.len() as u32,
+            photo_bytes: people.iter().map(|p| p.photo.len() as u64).sum(),
+        })
+    }
+
+    async fn greet_people(
+        &self,
+        _ctx: nats_rpc::RequestContext,
+        request: BoxStream<'static, Person>,
+    ) -> anyhow::Result<impl Stream<Item = Greeting> + Send> {
+        Ok(request.map(|person| Greeting {
+            message: format!("Hello, {} with {} bytes!", person.name, person.photo.len()),
+        }))
+    }
+}
+
+async fn start(transport: &LoopbackTransport, server: People) -> tokio::task::JoinHandle<()> {
+    let server = start_people_service_server(server, transport.clone())
+        .await
+        .expect("should be able to start server");
+    tokio::spawn(async move {
+        server.await.expect("server should keep running");
+    })
+}
+
+fn remote_code(error: &anyhow::Error) -> u16 {
+    error
+        .downcast_ref::<RemoteError>()
+        .unwrap_or_else(|| panic!("error should come from the service: {error:?}"))
+        .code
+}
+
+fn request(tenant_id: &str, id: u32, photo_size: u32) -> PersonRequest {
+    PersonRequest {
+        tenant_id: tenant_id.to_string(),
+        id,
+        photo_size,
+    }
+}
+
+#[tokio::test]
+async fn unary_calls_and_errors() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport, People::default()).await;
+    let client = PeopleServiceNatsClient::new(transport);
+
+    let found = client.get_person(request("acme", 7, 3)).await.unwrap();
+    assert_eq!(found, person("acme", 7, 3));
+
+    let options = CallOptions::new().header("X-Trace", "abc");
+    let reply = client
+        .get_person_with_options(request("acme", 7, 0), options)
+        .await
+        .unwrap();
+    assert_eq!(reply.message.id, 7);
+    assert_eq!(
+        reply.headers.get("X-Trace").map(|v| v.as_str()),
+        Some("abc")
+    );
+
+    let err = client.get_person(request("acme", 0, 0)).await.unwrap_err();
+    assert_eq!(remote_code(&err), 404);
+    assert_eq!(
+        err.to_string(),
+        "service returned error 404: person not found"
+    );
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn server_streaming() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport, People::default()).await;
+    let client = PeopleServiceNatsClient::new(transport);
+
+    let people = client
+        .list_people(request("acme", 3, 1))
+        .await
+        .unwrap()
+        .map(|person| person.unwrap().id)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(people, [1, 2, 3]);
+
+    let err = match client.list_people(request("acme", 0, 0)).await {
+        Ok(mut people) => people.next().await.expect("should end with an error"),
+        Err(e) => Err(e),
+    }
+    .unwrap_err();
+    assert_eq!(remote_code(&err), 404);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn client_streaming() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport, People::default()).await;
+    let client = PeopleServiceNatsClient::new(transport);
+
+    let people = stream::iter((1..=4).map(|id| person("acme", id, 2)));
+    let count = client.count_people(people).await.unwrap();
+    assert_eq!(count.people, 4);
+    assert_eq!(count.photo_bytes, 8);
+
+    let count = client.count_people(stream::empty()).await.unwrap();
+    assert_eq!(count.people, 0);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn bidirectional_streaming() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport, People::default()).await;
+    let client = PeopleServiceNatsClient::new(transport);
+
+    let people = stream::iter((1..=3).map(|id| person("acme", id, 0)));
+    let greetings = client
+        .greet_people(people)
+        .await
+        .unwrap()
+        .map(|greeting| greeting.unwrap().message)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        greetings,
+        [
+            "Hello, Person 1 with 0 bytes!",
+            "Hello, Person 2 with 0 bytes!",
+            "Hello, Person 3 with 0 bytes!",
+        ]
+    );
+
+    server.abort();
+}
+
+fn frame_headers(sequence: u64) -> async_nats::HeaderMap {
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(nats_rpc::SEQUENCE_HEADER, sequence.to_string().as_str());
+    headers
+}
+
+#[tokio::test]
+async fn broken_request_streams_fail_the_call() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport, People::default()).await;
+
+    // A client stream missing its second frame is rejected rather than counted
+    let inbox = transport.new_inbox();
+    let mut replies = transport.subscribe(inbox.clone(), None).await.unwrap();
+    let subject = "test.rpc.people.count_people".to_string();
+    let session = nats_rpc::open_session(&transport, subject, &inbox, &CallOptions::new())
+        .await
+        .unwrap();
+    for sequence in [0, 2] {
+        let payload = nats_rpc::encode(&person("acme", 1, 0));
+        let headers = frame_headers(sequence);
+        transport
+            .publish(session.clone(), None, headers, payload)
+            .await
+            .unwrap();
+    }
+    let reply = replies.next().await.unwrap();
+    let err = RemoteError::from_message(&reply).expect("call should fail");
+    assert_eq!(err.code, 400);
+
+    // The responses of a bidirectional stream whose request doesn't decode end with an error
+    let inbox = transport.new_inbox();
+    let replies = transport.subscribe(inbox.clone(), None).await.unwrap();
+    let subject = "test.rpc.people.greet_people".to_string();
+    let session = nats_rpc::open_session(&transport, subject, &inbox, &CallOptions::new())
+        .await
+        .unwrap();
+    let frames = [
+        nats_rpc::encode(&person("acme", 1, 0)),
+        bytes::Bytes::from_static(&[0xff]),
+    ];
+    for (sequence, payload) in frames.into_iter().enumerate() {
+        let headers = frame_headers(sequence as u64);
+        transport
+            .publish(session.clone(), None, headers, payload)
+            .await
+            .unwrap();
+    }
+    let mut greetings = Box::pin(nats_rpc::decode_frames::<Greeting>(replies));
+    let greeting = greetings.next().await.unwrap().unwrap();
+    assert_eq!(greeting.message.message, "Hello, Person 1 with 0 bytes!");
+    let err = greetings.next().await.unwrap().unwrap_err();
+    assert_eq!(remote_code(&err), 400);
+    assert!(greetings.next().await.is_none());
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn idle_request_streams_time_out() {
+    let transport = LoopbackTransport::new();
+    let idle_timeout = std::time::Duration::from_millis(100);
+    let server = People {
+        idle_timeout,
+        ..People::default()
+    };
+    let server = start(&transport, server).await;
+
+    // The client sends one request and then goes quiet without ending the stream
+    let inbox = transport.new_inbox();
+    let mut replies = transport.subscribe(inbox.clone(), None).await.unwrap();
+    let subject = "test.rpc.people.count_people".to_string();
+    let session = nats_rpc::open_session(&transport, subject, &inbox, &CallOptions::new())
+        .await
+        .unwrap();
+    let payload = nats_rpc::encode(&person("acme", 1, 0));
+    transport
+        .publish(session, None, frame_headers(0), payload)
+        .await
+        .unwrap();
+    let started = std::time::Instant::now();
+    let reply = replies.next().await.unwrap();
+    assert!(started.elapsed() >= idle_timeout);
+    let err = RemoteError::from_message(&reply).expect("call should time out");
+    assert_eq!(err.code, 408);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn abandoned_calls_release_their_slots() {
+    let transport = LoopbackTransport::new();
+    let idle_timeout = std::time::Duration::from_millis(200);
+    let server = People {
+        idle_timeout,
+        limit: Some(2),
+        ..People::default()
+    };
+    let server = start(&transport, server).await;
+
+    // Fill every slot with a client stream that never sends a request
+    let started = std::time::Instant::now();
+    for _ in 0..2 {
+        let inbox = transport.new_inbox();
+        let subject = "test.rpc.people.count_people".to_string();
+        nats_rpc::open_session(&transport, subject, &inbox, &CallOptions::new())
+            .await
+            .unwrap();
+    }
+
+    // Calls wait for a slot, and are handled once the abandoned calls time out
+    let client = PeopleServiceNatsClient::new(transport);
+    let found = client.get_person(request("acme", 1, 0)).await.unwrap();
+    assert_eq!(found.id, 1);
+    assert!(started.elapsed() >= idle_timeout);
+    let count = client
+        .count_people(stream::iter([person("acme", 1, 0)]))
+        .await
+        .unwrap();
+    assert_eq!(count.people, 1);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn publish_subscribe() {
+    let transport = LoopbackTransport::new();
+    let client = PeopleServiceNatsClient::new(transport.clone());
+    let mut added = client.subscribe_people_added().await.unwrap();
+
+    let publisher = PeopleServicePublisher::new(transport);
+    publisher
+        .publish_people_added(&person("acme", 1, 0))
+        .await
+        .unwrap();
+    publisher
+        .publish_people_added(&person("globex", 2, 0))
+        .await
+        .unwrap();
+    assert_eq!(added.next().await.unwrap().unwrap(), person("acme", 1, 0));
+    assert_eq!(added.next().await.unwrap().unwrap(), person("globex", 2, 0));
+
+    // Events can't be published for tenants that can't be used in a subject
+    let err = publisher
+        .publish_people_added(&person("a.b", 3, 0))
+        .await
+        .unwrap_err();
+    assert!(err.downcast_ref::<RemoteError>().is_none(), "{err:?}");
+}
+
+#[tokio::test]
+async fn chunking() {
+    // Every message larger than this is split into chunks
+    let transport = LoopbackTransport::new().with_max_payload(1000);
+    let server = start(&transport, People::default()).await;
+    let client = PeopleServiceNatsClient::new(transport);
+
+    // Large reply
+    let found = client.get_person(request("acme", 7, 4321)).await.unwrap();
+    assert_eq!(found, person("acme", 7, 4321));
+
+    // Large frames of a server stream
+    let people = client
+        .list_people(request("acme", 3, 2500))
+        .await
+        .unwrap()
+        .map(|person| person.unwrap())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        people,
+        (1..=3)
+            .map(|id| person("acme", id, 2500))
+            .collect::<Vec<_>>()
+    );
+
+    // Large frames of a client stream
+    let people = stream::iter((1..=3).map(|id| person("acme", id, 3000)));
+    let count = client.count_people(people).await.unwrap();
+    assert_eq!(count.people, 3);
+    assert_eq!(count.photo_bytes, 9000);
+
+    // Large frames in both directions
+    let people = stream::iter((1..=2).map(|id| person("acme", id, 2000)));
+    let greetings = client
+        .greet_people(people)
+        .await
+        .unwrap()
+        .map(|greeting| greeting.unwrap().message)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(
+        greetings,
+        [
+            "Hello, Person 1 with 2000 bytes!",
+            "Hello, Person 2 with 2000 bytes!",
+        ]
+    );
+
+    // Errors are still received when requests are sent in chunks
+    let err = client
+        .get_person(request(&"a".repeat(1500), 0, 0))
+        .await
+        .unwrap_err();
+    assert_eq!(remote_code(&err), 404);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn oversized_chunked_requests_fail() {
+    let transport = LoopbackTransport::new().with_max_payload(1000);
+    let server = People {
+        max_request_size: 3000,
+        ..People::default()
+    };
+    let server = start(&transport, server).await;
+    let client = PeopleServiceNatsClient::new(transport);
+
+    let found = client
+        .get_person(request(&"a".repeat(2500), 1, 0))
+        .await
+        .unwrap();
+    assert_eq!(found.id, 1);
+
+    // The caller is told the request is too large instead of waiting for a reply
+    let err = client
+        .get_person(request(&"a".repeat(5000), 1, 0))
+        .await
+        .unwrap_err();
+    assert_eq!(remote_code(&err), 413);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn field_subjects() {
+    let transport = LoopbackTransport::new();
+    let server = start(&transport, People::default()).await;
+    let client = PeopleServiceNatsClient::new(transport.clone());
+
+    // Each tenant's requests are sent on its own subject
+    let mut acme = transport
+        .subscribe("test.rpc.acme.get".to_string(), None)
+        .await
+        .unwrap();
+    client.get_person(request("acme", 1, 0)).await.unwrap();
+    let sent = acme.next().await.unwrap();
+    assert_eq!(sent.subject.as_str(), "test.rpc.acme.get");
+
+    let people = client
+        .list_people(request("globex", 2, 0))
+        .await
+        .unwrap()
+        .map(|person| person.unwrap().tenant_id)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(people, ["globex", "globex"]);
+
+    // Clients don't send requests whose fields can't be used in a subject
+    let err = client.get_person(request("a.b", 1, 0)).await.unwrap_err();
+    assert!(err.downcast_ref::<RemoteError>().is_none(), "{err:?}");
+    let err = client.get_person(request("", 1, 0)).await.unwrap_err();
+    assert!(err.downcast_ref::<RemoteError>().is_none(), "{err:?}");
+
+    // Servers reject requests whose fields don't match the subject they arrived on
+    let payload = nats_rpc::encode(&request("acme", 1, 0));
+    let reply = transport
+        .request(
+            "test.rpc.globex.get".to_string(),
+            payload,
+            CallOptions::new(),
+        )
+        .await
+        .unwrap();
+    let err = RemoteError::from_message(&reply).expect("request should be rejected");
+    assert_eq!(err.code, 400);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn subject_prefixes() {
+    let transport = LoopbackTransport::new();
+    let server = start(
+        &transport,
+        People {
+            prefix: "tenant.acme".to_string(),
+            ..People::default()
+        },
+    )
+    .await;
+
+    let client = PeopleServiceNatsClient::builder(transport.clone())
+        .subject_prefix("tenant.acme")
+        .build();
+    assert_eq!(
+        client.get_person(request("acme", 1, 0)).await.unwrap().id,
+        1
+    );
+    let count = client
+        .count_people(stream::iter([person("acme", 1, 0)]))
+        .await
+        .unwrap();
+    assert_eq!(count.people, 1);
+
+    // Nothing serves the default prefix
+    let client = PeopleServiceNatsClient::new(transport);
+    client.get_person(request("acme", 1, 0)).await.unwrap_err();
+
+    server.abort();
+}
+
+// The `auto_subject_prefix` option implements the client trait on the connection itself
+#[test]
+fn clients_are_implemented_for_the_connection() {
+    fn client<T: PeopleServiceClient>() {}
+    client::<async_nats::Client>();
+    client::<PeopleServiceNatsClient<LoopbackTransport>>();
+}